@@ -70,6 +70,12 @@ fi
 rust_src="${release_dir}/../rust/pinput"
 rust_build_dir="${rust_src}/target/release"
 pinput_exe_name="pinput${exe_suffix}"
+# Only Linux uses the memory helper.
+if [ "${os}" = 'linux' ]; then
+  helper_exe_name='pinput-helper'
+else
+  helper_exe_name=''
+fi
 archive_name="${release_dir}/artifacts/pinput-rust-${os}-${arch}-${version}.zip"
 
 # Clean previous build directory and output archive.
@@ -104,30 +110,37 @@ rm -rf "${rust_build_dir}" "${archive_name}" \
     --remap-path-prefix=${build_dir}= \
     ${linker_flags} \
   "
+  # The memory helper is its own crate so it doesn't link SDL,
+  # but `post_build.rs` expects to find it next to `pinput`.
+  if [ "${os}" = 'linux' ]; then
+    cargo build --release --manifest-path ../pinput-helper/Cargo.toml --target-dir target
+  fi
   cargo post build --release
 )
 
 # Check executable for unwanted strings.
 unwanted_pattern="${user_name}|${machine_name}"
 
-unwanted="$(\
-  strings_cmd "${rust_build_dir}/${pinput_exe_name}" \
-  | grep -i -E "${unwanted_pattern}" \
-  || true \
-)"
-if [ -n "${unwanted}" ] && [ -z "${BYPASS_STRING_CHECK:-}" ]; then
-  printf "Unwanted strings found in %s:\n%s\n" \
-    "${rust_build_dir}/${pinput_exe_name}" \
-    "${unwanted}" \
-    1>&2
-  exit 1
-fi
+for exe_name in "${pinput_exe_name}" ${helper_exe_name}; do
+  unwanted="$(\
+    strings_cmd "${rust_build_dir}/${exe_name}" \
+    | grep -i -E "${unwanted_pattern}" \
+    || true \
+  )"
+  if [ -n "${unwanted}" ] && [ -z "${BYPASS_STRING_CHECK:-}" ]; then
+    printf "Unwanted strings found in %s:\n%s\n" \
+      "${rust_build_dir}/${exe_name}" \
+      "${unwanted}" \
+      1>&2
+    exit 1
+  fi
+done
 
 # Zip it with a descriptive archive name.
 # `zip` doesn't have an option to change the working directory.
 (
   cd "${rust_build_dir}"
-  zip_cmd "${archive_name}" "${pinput_exe_name}"
+  zip_cmd "${archive_name}" "${pinput_exe_name}" ${helper_exe_name}
 )
 
 unwanted="$(\
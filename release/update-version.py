@@ -53,6 +53,20 @@ def main():
             f.write(contents)
             f.truncate()
 
+    for crate in ['pinput-protocol', 'pinput-fake-runtime']:
+        with open(base_dir / 'rust' / crate / 'Cargo.toml', 'r+') as f:
+            contents = f.read()
+            contents = re.sub(
+                fr'^(name = "{crate}"\nversion = ")(\d+\.\d+\.\d+)(")$',
+                fr'\g<1>{vformat(next_version)}\g<3>',
+                contents,
+                count=1,
+                flags=re.MULTILINE
+            )
+            f.seek(0)
+            f.write(contents)
+            f.truncate()
+
     for filename in ['Cargo.toml', 'Cargo.lock']:
         with open(base_dir / 'WASM-4/pinput_tester' / filename, 'r+') as f:
             contents = f.read()
@@ -81,6 +95,19 @@ def main():
             f.write(contents)
             f.truncate()
 
+    with open(base_dir / 'TIC-80/pinput.lua', 'r+') as f:
+        contents = f.read()
+        contents = re.sub(
+            r'^(--.* v)(\d+\.\d+\.\d+)$',
+            fr'\g<1>{vformat(next_version)}',
+            contents,
+            count=1,
+            flags=re.MULTILINE
+        )
+        f.seek(0)
+        f.write(contents)
+        f.truncate()
+
     with open(base_dir / 'web/pinput.js', 'r+') as f:
         contents = f.read()
         contents = re.sub(
@@ -0,0 +1,326 @@
+//! Stand-in for a PICO-8, WASM-4, or TIC-80 runtime running a Pinput-enabled cartridge.
+//!
+//! Allocates an anonymous read-write mapping laid out like the runtime's memory,
+//! writes Pinput magic into its GPIO block like `pi_init()` does,
+//! and prints the gamepad block every frame. It can also play back scripted rumble values.
+//! Pinput finds runtimes by executable name, so this re-executes itself under the name
+//! of the runtime it's impersonating.
+
+use pinput_protocol::{
+    has_magic, offsets, write_magic, PinputGamepad, PINPUT_GAMEPAD_SIZE, PINPUT_GPIO_SIZE,
+    PINPUT_MAX_GAMEPADS,
+};
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 60 Hz, like the real runtimes.
+const FRAME_DURATION: Duration = Duration::from_micros(16_667);
+
+/// How long each scripted rumble step lasts by default.
+const DEFAULT_RUMBLE_FRAMES: u64 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Flavor {
+    Pico8,
+    Wasm4,
+    Tic80,
+}
+
+impl Flavor {
+    /// An executable name that Pinput's process detection accepts for this runtime.
+    fn executable_name(self) -> &'static str {
+        match self {
+            Flavor::Pico8 => "pico8",
+            Flavor::Wasm4 => "wasm4",
+            Flavor::Tic80 => "tic80",
+        }
+    }
+
+    /// Size of the runtime's main memory block.
+    /// PICO-8 and WASM-4 have 64 KiB of addressable RAM, and TIC-80 has 96 KiB.
+    fn memory_size(self) -> usize {
+        match self {
+            Flavor::Pico8 | Flavor::Wasm4 => 0x10000,
+            Flavor::Tic80 => 0x18000,
+        }
+    }
+
+    /// Where the GPIO block lives inside the runtime's main memory block.
+    fn gpio_offset(self) -> usize {
+        match self {
+            // PICO-8 GPIO pins.
+            Flavor::Pico8 => 0x5f80,
+            // WASM-4 reserved area used by the Pinput tester cartridge.
+            Flavor::Wasm4 => 0x20,
+            // Unused RAM after the system font, as in the TIC-80 client.
+            Flavor::Tic80 => 0x14e04,
+        }
+    }
+}
+
+impl Display for Flavor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Flavor::Pico8 => "PICO-8",
+            Flavor::Wasm4 => "WASM-4",
+            Flavor::Tic80 => "TIC-80",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug)]
+struct Options {
+    flavor: Flavor,
+    /// Rumble values to cycle through once connected, as `(lo_freq_rumble, hi_freq_rumble)`.
+    rumble: Vec<(u8, u8)>,
+    /// How many frames each rumble step lasts.
+    rumble_frames: u64,
+    /// Exit after this many frames instead of running until killed.
+    frames: Option<u64>,
+}
+
+fn parse_rumble_step(step: &str) -> Result<(u8, u8), String> {
+    let (lo, hi) = step
+        .split_once(':')
+        .ok_or_else(|| format!("rumble step `{step}` should look like `LO:HI`"))?;
+    let parse = |v: &str| {
+        v.parse::<u8>()
+            .map_err(|e| format!("bad rumble value `{v}`: {e}"))
+    };
+    Ok((parse(lo)?, parse(hi)?))
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        flavor: Flavor::Pico8,
+        rumble: Vec::new(),
+        rumble_frames: DEFAULT_RUMBLE_FRAMES,
+        frames: None,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("`{arg}` needs a value"));
+        match arg.as_str() {
+            "--flavor" => {
+                options.flavor = match value()?.as_str() {
+                    "pico8" => Flavor::Pico8,
+                    "wasm4" => Flavor::Wasm4,
+                    "tic80" => Flavor::Tic80,
+                    other => return Err(format!("unknown flavor `{other}`")),
+                }
+            }
+            "--rumble" => {
+                options.rumble = value()?
+                    .split(',')
+                    .map(parse_rumble_step)
+                    .collect::<Result<_, _>>()?;
+            }
+            "--rumble-frames" => {
+                options.rumble_frames = value()?
+                    .parse()
+                    .map_err(|e| format!("bad `--rumble-frames`: {e}"))?;
+            }
+            "--frames" => {
+                options.frames = Some(
+                    value()?
+                        .parse()
+                        .map_err(|e| format!("bad `--frames`: {e}"))?,
+                );
+            }
+            other => return Err(format!("unknown argument `{other}`")),
+        }
+    }
+    if options.rumble_frames == 0 {
+        return Err("`--rumble-frames` must be at least 1".to_owned());
+    }
+    Ok(options)
+}
+
+fn print_usage(name: &str) {
+    println!(
+        "usage: {name} [--flavor pico8|wasm4|tic80] [--rumble LO:HI[,LO:HI...]] [--rumble-frames N] [--frames N]"
+    );
+}
+
+/// Anonymous read-write memory, like the mappings Pinput looks for in real runtimes.
+#[cfg(unix)]
+struct AnonymousMapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+#[cfg(unix)]
+impl AnonymousMapping {
+    fn new(len: usize) -> std::io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    /// Pointer to the GPIO block at `offset`.
+    /// Pinput writes here from another process, so all access must be volatile.
+    fn gpio(&self, offset: usize) -> *mut [u8; PINPUT_GPIO_SIZE] {
+        assert!(offset + PINPUT_GPIO_SIZE <= self.len);
+        unsafe { self.ptr.add(offset) as *mut [u8; PINPUT_GPIO_SIZE] }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for AnonymousMapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// If we aren't already running under the runtime's executable name,
+/// hard-link ourselves to that name in a temporary directory and execute the link.
+/// Pinput reads the executable path of each process, which for a hard link is the link's path.
+/// Symlinks won't do because the kernel reports the resolved path.
+#[cfg(unix)]
+fn exec_as_runtime(flavor: Flavor) -> std::io::Result<()> {
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    let exe = env::current_exe()?;
+    let name = flavor.executable_name();
+    if exe.file_name().is_some_and(|file_name| file_name == name) {
+        return Ok(());
+    }
+
+    // One directory per process so that concurrent instances don't unlink each other's executables.
+    let alias_dir = env::temp_dir().join(format!("pinput-fake-runtime-{}", std::process::id()));
+    std::fs::create_dir_all(&alias_dir)?;
+    let alias = alias_dir.join(name);
+    let _ = std::fs::remove_file(&alias);
+    if std::fs::hard_link(&exe, &alias).is_err() {
+        // Probably a different filesystem.
+        std::fs::copy(&exe, &alias)?;
+    }
+
+    // Only returns on failure.
+    Err(Command::new(&alias).args(env::args_os().skip(1)).exec())
+}
+
+fn print_gpio(frame: u64, gpio: &[u8; PINPUT_GPIO_SIZE]) {
+    if has_magic(gpio) {
+        println!("frame {frame}: waiting for Pinput");
+        return;
+    }
+    println!("frame {frame}: connected");
+    for (slot, record) in gpio.chunks_exact(PINPUT_GAMEPAD_SIZE).enumerate() {
+        let hex: Vec<String> = record.iter().map(|b| format!("{b:02x}")).collect();
+        let mut bytes = [0u8; PINPUT_GAMEPAD_SIZE];
+        bytes.copy_from_slice(record);
+        let gamepad = PinputGamepad::from_bytes(&bytes);
+        println!("  {slot}: {} {:?}", hex.join(" "), { gamepad.flags });
+    }
+}
+
+#[cfg(unix)]
+fn run(options: Options) -> std::io::Result<()> {
+    exec_as_runtime(options.flavor)?;
+
+    let memory = AnonymousMapping::new(options.flavor.memory_size())?;
+    let gpio = memory.gpio(options.flavor.gpio_offset());
+
+    // `pi_init()`
+    let mut magic = [0u8; PINPUT_GPIO_SIZE];
+    write_magic(&mut magic);
+    unsafe { std::ptr::write_volatile(gpio, magic) };
+
+    println!(
+        "{} stand-in running: PID {}, GPIO at {:#x}",
+        options.flavor,
+        std::process::id(),
+        gpio as usize
+    );
+
+    let start = Instant::now();
+    let mut frame = 0u64;
+    while options.frames.is_none_or(|frames| frame < frames) {
+        let bytes = unsafe { std::ptr::read_volatile(gpio) };
+
+        if !has_magic(&bytes) && !options.rumble.is_empty() {
+            let step = (frame / options.rumble_frames) as usize % options.rumble.len();
+            let (lo_freq_rumble, hi_freq_rumble) = options.rumble[step];
+            for slot in 0..PINPUT_MAX_GAMEPADS {
+                let record = slot * PINPUT_GAMEPAD_SIZE;
+                unsafe {
+                    let base = gpio as *mut u8;
+                    std::ptr::write_volatile(
+                        base.add(record + offsets::LO_FREQ_RUMBLE),
+                        lo_freq_rumble,
+                    );
+                    std::ptr::write_volatile(
+                        base.add(record + offsets::HI_FREQ_RUMBLE),
+                        hi_freq_rumble,
+                    );
+                }
+            }
+        }
+
+        print_gpio(frame, &bytes);
+
+        frame += 1;
+        if let Some(remaining) =
+            (start + FRAME_DURATION * frame as u32).checked_duration_since(Instant::now())
+        {
+            thread::sleep(remaining);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn run(_options: Options) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "the fake runtime currently only supports Unix-like systems",
+    ))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let name = args
+        .first()
+        .cloned()
+        .unwrap_or_else(|| "pinput-fake-runtime".to_owned());
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        print_usage(&name);
+        return;
+    }
+
+    let options = match parse_args(args.get(1..).unwrap_or_default()) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{message}");
+            print_usage(&name);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(err) = run(options) {
+        eprintln!("Fake runtime failed: {err}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,40 @@
+//! Reads and writes runtime memory on behalf of `pinput`, which starts it with a socket on stdin.
+//! This is the only executable that needs the CAP_SYS_PTRACE capability,
+//! so it leaves out the `bridge` feature and doesn't link SDL, Buttplug, or Tokio.
+
+use std::env;
+use std::process;
+
+use pinput::constants::DEFAULT_SCAN_BUDGET;
+
+fn main() {
+    let mut scan_budget = DEFAULT_SCAN_BUDGET;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--scan-budget", Some(size)) => match size.parse() {
+                Ok(size) => scan_budget = size,
+                Err(_) => usage(),
+            },
+            _ => usage(),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Err(err) = pinput::memory_helper::serve(scan_budget) {
+        eprintln!("pinput-helper: {err}");
+        process::exit(1);
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = scan_budget;
+        eprintln!("pinput-helper is only needed on Linux");
+        process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: pinput-helper [--scan-budget BYTES]");
+    eprintln!("pinput starts this itself; there's no need to run it by hand.");
+    process::exit(2);
+}
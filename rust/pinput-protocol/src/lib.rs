@@ -0,0 +1,437 @@
+//! Pinput wire format: the 128-byte GPIO block shared between Pinput and a runtime.
+//!
+//! This crate is `no_std` so that it can be used both by the Pinput host app
+//! and by cartridges compiled to WASM, like the WASM-4 Pinput tester.
+//! The layout must stay in sync with `PICO-8/pinput.lua`.
+
+#![no_std]
+
+use bitflags::bitflags;
+
+/// Pinput can fit this many gamepads into the GPIO area.
+pub const PINPUT_MAX_GAMEPADS: usize = 8;
+
+/// Size of a single gamepad record in bytes (`pi_gamepad_stride` in `pinput.lua`).
+pub const PINPUT_GAMEPAD_SIZE: usize = 16;
+
+/// Size of the whole GPIO block in bytes.
+pub const PINPUT_GPIO_SIZE: usize = PINPUT_MAX_GAMEPADS * PINPUT_GAMEPAD_SIZE;
+
+/// Magic as a big-endian integer, for readability.
+pub const PINPUT_MAGIC_U128: u128 = 0x0220c74677ab446ebedc7fd6d277984d;
+
+/// Magic byte sequence we use to identify Pinput-enabled cartridges.
+/// Cartridges write this to the start of the GPIO block, and Pinput clears it once connected.
+pub const PINPUT_MAGIC: [u8; 16] = PINPUT_MAGIC_U128.to_be_bytes();
+
+/// Offsets of each field inside a gamepad record.
+/// Named after the corresponding `pi_*_offset` constants in `pinput.lua`.
+pub mod offsets {
+    pub const FLAGS: usize = 0;
+    pub const BATTERY: usize = 1;
+    pub const BUTTONS: usize = 2;
+    pub const LEFT_TRIGGER: usize = 4;
+    pub const RIGHT_TRIGGER: usize = 5;
+    pub const LEFT_STICK_X: usize = 6;
+    pub const LEFT_STICK_Y: usize = 8;
+    pub const RIGHT_STICK_X: usize = 10;
+    pub const RIGHT_STICK_Y: usize = 12;
+    pub const LO_FREQ_RUMBLE: usize = 14;
+    pub const HI_FREQ_RUMBLE: usize = 15;
+}
+
+bitflags! {
+    /// Gamepad informational flags.
+    #[derive(Default)]
+    pub struct PinputGamepadFlags: u8 {
+        /// This gamepad is connected.
+        const CONNECTED = 1 << 0;
+
+        /// This gamepad has a battery.
+        /// If this is true, the `battery` field and the `Charging` flag may be non-zero.
+        const HAS_BATTERY = 1 << 1;
+
+        /// This gamepad is currently charging its battery.
+        const CHARGING = 1 << 2;
+
+        /// Does this controller have a usable guide button?
+        /// Not all Apple-supported gamepads have a guide button,
+        /// and versions of Pinput on other platforms might not have access to them
+        /// (XInput on Windows, for example).
+        const HAS_GUIDE_BUTTON = 1 << 3;
+
+        /// Does this controller have a misc or touchpad-click button?
+        const HAS_MISC_BUTTON = 1 << 4;
+
+        /// Does this controller support vibration?
+        const HAS_RUMBLE = 1 << 5;
+
+        /// Is this gamepad actually a haptic device?
+        const HAPTIC_DEVICE = 1 << 6;
+    }
+}
+
+bitflags! {
+    /// Flags indicating which buttons are currently pressed.
+    /// Same as <https://docs.microsoft.com/en-us/windows/win32/api/xinput/ns-xinput-xinput_gamepad>
+    /// with the addition of guide and misc buttons in the two unused bits.
+    #[derive(Default)]
+    pub struct PinputGamepadButtons: u16 {
+        const DPAD_UP = 1 << 0;
+        const DPAD_DOWN = 1 << 1;
+        const DPAD_LEFT = 1 << 2;
+        const DPAD_RIGHT = 1 << 3;
+
+        const START = 1 << 4;
+        const BACK = 1 << 5;
+
+        const LEFT_STICK = 1 << 6;
+        const RIGHT_STICK = 1 << 7;
+
+        const LEFT_BUMPER = 1 << 8;
+        const RIGHT_BUMPER = 1 << 9;
+
+        const GUIDE = 1 << 10;
+        /// We map both SDL `Misc1` and `Touchpad` to this.
+        /// No current controllers have both buttons, and they're in approximately the same spot.
+        const MISC = 1 << 11;
+
+        const A = 1 << 12;
+        const B = 1 << 13;
+        const X = 1 << 14;
+        const Y = 1 << 15;
+    }
+}
+
+/// Structure representing a gamepad to the runtime.
+/// Based on <https://docs.microsoft.com/en-us/windows/win32/api/xinput/ns-xinput-xinput_gamepad>
+/// and <https://docs.microsoft.com/en-us/windows/win32/api/xinput/ns-xinput-xinput_vibration>
+/// but prefixed with controller flags and a battery meter, and with smaller rumble types to fit
+/// into a convenient size (16 bytes). All fields are written to the runtime,
+/// except for the rumble fields, which are read from the runtime.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PinputGamepad {
+    pub flags: PinputGamepadFlags,
+    /// 0 for empty or not present, max value for fully charged.
+    pub battery: u8,
+    pub buttons: PinputGamepadButtons,
+
+    pub left_trigger: u8,
+    pub right_trigger: u8,
+
+    pub left_stick_x: i16,
+    pub left_stick_y: i16,
+
+    pub right_stick_x: i16,
+    pub right_stick_y: i16,
+
+    /// Output from the runtime.
+    pub lo_freq_rumble: u8,
+    /// Output from the runtime.
+    pub hi_freq_rumble: u8,
+}
+
+/// Fill GPIO with gamepads.
+pub type PinputGamepadArray = [PinputGamepad; PINPUT_MAX_GAMEPADS];
+
+fn read_u16(bytes: &[u8; PINPUT_GAMEPAD_SIZE], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_i16(bytes: &[u8; PINPUT_GAMEPAD_SIZE], offset: usize) -> i16 {
+    read_u16(bytes, offset) as i16
+}
+
+fn write_u16(bytes: &mut [u8; PINPUT_GAMEPAD_SIZE], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+impl PinputGamepad {
+    /// Encode this gamepad as it appears in runtime memory.
+    /// Multi-byte fields are little-endian, as read by PICO-8's `peek2`.
+    pub fn to_bytes(&self) -> [u8; PINPUT_GAMEPAD_SIZE] {
+        let mut bytes = [0u8; PINPUT_GAMEPAD_SIZE];
+        bytes[offsets::FLAGS] = self.flags.bits();
+        bytes[offsets::BATTERY] = self.battery;
+        write_u16(&mut bytes, offsets::BUTTONS, { self.buttons }.bits());
+        bytes[offsets::LEFT_TRIGGER] = self.left_trigger;
+        bytes[offsets::RIGHT_TRIGGER] = self.right_trigger;
+        write_u16(&mut bytes, offsets::LEFT_STICK_X, self.left_stick_x as u16);
+        write_u16(&mut bytes, offsets::LEFT_STICK_Y, self.left_stick_y as u16);
+        write_u16(
+            &mut bytes,
+            offsets::RIGHT_STICK_X,
+            self.right_stick_x as u16,
+        );
+        write_u16(
+            &mut bytes,
+            offsets::RIGHT_STICK_Y,
+            self.right_stick_y as u16,
+        );
+        bytes[offsets::LO_FREQ_RUMBLE] = self.lo_freq_rumble;
+        bytes[offsets::HI_FREQ_RUMBLE] = self.hi_freq_rumble;
+        bytes
+    }
+
+    /// Decode a gamepad from runtime memory.
+    /// Unknown flag bits are dropped.
+    pub fn from_bytes(bytes: &[u8; PINPUT_GAMEPAD_SIZE]) -> Self {
+        Self {
+            flags: PinputGamepadFlags::from_bits_truncate(bytes[offsets::FLAGS]),
+            battery: bytes[offsets::BATTERY],
+            buttons: PinputGamepadButtons::from_bits_truncate(read_u16(bytes, offsets::BUTTONS)),
+            left_trigger: bytes[offsets::LEFT_TRIGGER],
+            right_trigger: bytes[offsets::RIGHT_TRIGGER],
+            left_stick_x: read_i16(bytes, offsets::LEFT_STICK_X),
+            left_stick_y: read_i16(bytes, offsets::LEFT_STICK_Y),
+            right_stick_x: read_i16(bytes, offsets::RIGHT_STICK_X),
+            right_stick_y: read_i16(bytes, offsets::RIGHT_STICK_Y),
+            lo_freq_rumble: bytes[offsets::LO_FREQ_RUMBLE],
+            hi_freq_rumble: bytes[offsets::HI_FREQ_RUMBLE],
+        }
+    }
+}
+
+/// Encode all gamepads as the full GPIO block.
+pub fn encode_gamepads(gamepads: &PinputGamepadArray) -> [u8; PINPUT_GPIO_SIZE] {
+    let mut gpio = [0u8; PINPUT_GPIO_SIZE];
+    for (chunk, gamepad) in gpio.chunks_exact_mut(PINPUT_GAMEPAD_SIZE).zip(gamepads) {
+        chunk.copy_from_slice(&gamepad.to_bytes());
+    }
+    gpio
+}
+
+/// Decode all gamepads from the full GPIO block.
+pub fn decode_gamepads(gpio: &[u8; PINPUT_GPIO_SIZE]) -> PinputGamepadArray {
+    let mut gamepads = PinputGamepadArray::default();
+    for (gamepad, chunk) in gamepads
+        .iter_mut()
+        .zip(gpio.chunks_exact(PINPUT_GAMEPAD_SIZE))
+    {
+        let mut bytes = [0u8; PINPUT_GAMEPAD_SIZE];
+        bytes.copy_from_slice(chunk);
+        *gamepad = PinputGamepad::from_bytes(&bytes);
+    }
+    gamepads
+}
+
+/// Does this GPIO block start with Pinput magic?
+/// If so, a cartridge has called `pi_init()` and is waiting for Pinput to connect.
+pub fn has_magic(gpio: &[u8]) -> bool {
+    match gpio.get(..PINPUT_MAGIC.len()) {
+        Some(bytes) => {
+            let mut magic = [0u8; 16];
+            magic.copy_from_slice(bytes);
+            u128::from_be_bytes(magic) == PINPUT_MAGIC_U128
+        }
+        None => false,
+    }
+}
+
+/// Write Pinput magic to the start of a GPIO block, like `pi_init()` does.
+///
+/// Both this and `has_magic` work on the integer form of the magic rather than the byte array,
+/// so that cartridges compiled to WASM don't carry a copy of the magic in their data segment,
+/// where Pinput might find it before the real one.
+pub fn write_magic(gpio: &mut [u8]) {
+    let magic = &mut gpio[..PINPUT_MAGIC.len()];
+    unsafe {
+        core::ptr::write_unaligned(magic.as_mut_ptr() as *mut u128, PINPUT_MAGIC_U128.to_be())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::{align_of, size_of};
+    use core::ptr::addr_of;
+
+    /// Byte offset of a field inside a `PinputGamepad`.
+    macro_rules! offset_of_field {
+        ($field:ident) => {{
+            let gamepad = PinputGamepad::default();
+            let base = addr_of!(gamepad) as usize;
+            addr_of!(gamepad.$field) as usize - base
+        }};
+    }
+
+    #[test]
+    fn gamepad_size_matches_stride() {
+        assert_eq!(size_of::<PinputGamepad>(), PINPUT_GAMEPAD_SIZE);
+        assert_eq!(align_of::<PinputGamepad>(), 1);
+        assert_eq!(size_of::<PinputGamepadArray>(), PINPUT_GPIO_SIZE);
+        assert_eq!(PINPUT_GPIO_SIZE, 128);
+    }
+
+    #[test]
+    fn field_offsets_match_pinput_lua() {
+        assert_eq!(offset_of_field!(flags), offsets::FLAGS);
+        assert_eq!(offset_of_field!(battery), offsets::BATTERY);
+        assert_eq!(offset_of_field!(buttons), offsets::BUTTONS);
+        assert_eq!(offset_of_field!(left_trigger), offsets::LEFT_TRIGGER);
+        assert_eq!(offset_of_field!(right_trigger), offsets::RIGHT_TRIGGER);
+        assert_eq!(offset_of_field!(left_stick_x), offsets::LEFT_STICK_X);
+        assert_eq!(offset_of_field!(left_stick_y), offsets::LEFT_STICK_Y);
+        assert_eq!(offset_of_field!(right_stick_x), offsets::RIGHT_STICK_X);
+        assert_eq!(offset_of_field!(right_stick_y), offsets::RIGHT_STICK_Y);
+        assert_eq!(offset_of_field!(lo_freq_rumble), offsets::LO_FREQ_RUMBLE);
+        assert_eq!(offset_of_field!(hi_freq_rumble), offsets::HI_FREQ_RUMBLE);
+    }
+
+    /// The PICO-8 and TIC-80 clients, which hard-code the layout.
+    const PINPUT_LUA: [(&str, &str); 2] = [
+        ("PICO-8", include_str!("../../../PICO-8/pinput.lua")),
+        ("TIC-80", include_str!("../../../TIC-80/pinput.lua")),
+    ];
+
+    /// Value of a top-level `name = number` assignment in a Lua client.
+    fn lua_constant(source: &str, name: &str) -> usize {
+        source
+            .lines()
+            .find_map(|line| {
+                let (lhs, rhs) = line.split_once('=')?;
+                if lhs.trim() != name {
+                    return None;
+                }
+                let rhs = rhs.trim();
+                match rhs.strip_prefix("0x") {
+                    Some(hex) => usize::from_str_radix(hex, 16).ok(),
+                    None => rhs.parse().ok(),
+                }
+            })
+            .unwrap_or_else(|| panic!("no numeric {name} in pinput.lua"))
+    }
+
+    #[test]
+    fn offsets_match_pinput_lua_constants() {
+        for (client, source) in PINPUT_LUA {
+            let constant = |name| lua_constant(source, name);
+            let trigger = |i| constant("pi_trigger_offset") + i * constant("pi_trigger_stride");
+            let axis = |i| constant("pi_axis_offset") + i * constant("pi_axis_stride");
+            let rumble = |i| constant("pi_rumble_offset") + i * constant("pi_rumble_stride");
+
+            assert_eq!(constant("pi_num_players"), PINPUT_MAX_GAMEPADS, "{client}");
+            assert_eq!(
+                constant("pi_gamepad_stride"),
+                PINPUT_GAMEPAD_SIZE,
+                "{client}"
+            );
+            assert_eq!(constant("pi_flags_offset"), offsets::FLAGS, "{client}");
+            assert_eq!(constant("pi_battery_offset"), offsets::BATTERY, "{client}");
+            assert_eq!(constant("pi_buttons_offset"), offsets::BUTTONS, "{client}");
+            assert_eq!(
+                trigger(constant("pi_lt")),
+                offsets::LEFT_TRIGGER,
+                "{client}"
+            );
+            assert_eq!(
+                trigger(constant("pi_rt")),
+                offsets::RIGHT_TRIGGER,
+                "{client}"
+            );
+            assert_eq!(axis(constant("pi_lx")), offsets::LEFT_STICK_X, "{client}");
+            assert_eq!(axis(constant("pi_ly")), offsets::LEFT_STICK_Y, "{client}");
+            assert_eq!(axis(constant("pi_rx")), offsets::RIGHT_STICK_X, "{client}");
+            assert_eq!(axis(constant("pi_ry")), offsets::RIGHT_STICK_Y, "{client}");
+            assert_eq!(
+                rumble(constant("pi_lo")),
+                offsets::LO_FREQ_RUMBLE,
+                "{client}"
+            );
+            assert_eq!(
+                rumble(constant("pi_hi")),
+                offsets::HI_FREQ_RUMBLE,
+                "{client}"
+            );
+        }
+    }
+
+    #[test]
+    fn encoding_matches_in_memory_layout() {
+        let gamepad = PinputGamepad {
+            flags: PinputGamepadFlags::CONNECTED | PinputGamepadFlags::HAS_RUMBLE,
+            battery: 0xab,
+            buttons: PinputGamepadButtons::A | PinputGamepadButtons::DPAD_UP,
+            left_trigger: 0x12,
+            right_trigger: 0x34,
+            left_stick_x: -2,
+            left_stick_y: 0x1234,
+            right_stick_x: i16::MIN,
+            right_stick_y: i16::MAX,
+            lo_freq_rumble: 0x56,
+            hi_freq_rumble: 0x78,
+        };
+        let bytes = gamepad.to_bytes();
+        assert_eq!(
+            bytes,
+            [
+                0x21, 0xab, 0x01, 0x10, 0x12, 0x34, 0xfe, 0xff, 0x34, 0x12, 0x00, 0x80, 0xff, 0x7f,
+                0x56, 0x78
+            ]
+        );
+        // On little-endian hosts, the packed struct has exactly the same bytes.
+        #[cfg(target_endian = "little")]
+        {
+            let raw: [u8; PINPUT_GAMEPAD_SIZE] = unsafe { core::mem::transmute(gamepad) };
+            assert_eq!(raw, bytes);
+        }
+    }
+
+    #[test]
+    fn gamepad_round_trips() {
+        let gamepad = PinputGamepad {
+            flags: PinputGamepadFlags::CONNECTED | PinputGamepadFlags::HAPTIC_DEVICE,
+            battery: 1,
+            buttons: PinputGamepadButtons::all(),
+            left_trigger: 2,
+            right_trigger: 3,
+            left_stick_x: 4,
+            left_stick_y: -5,
+            right_stick_x: 6,
+            right_stick_y: -7,
+            lo_freq_rumble: 8,
+            hi_freq_rumble: 9,
+        };
+        let decoded = PinputGamepad::from_bytes(&gamepad.to_bytes());
+        assert_eq!(decoded.to_bytes(), gamepad.to_bytes());
+    }
+
+    #[test]
+    fn gamepad_array_uses_16_byte_stride() {
+        let mut gamepads = PinputGamepadArray::default();
+        for (i, gamepad) in gamepads.iter_mut().enumerate() {
+            gamepad.battery = i as u8;
+            gamepad.hi_freq_rumble = 0x80 | i as u8;
+        }
+        let gpio = encode_gamepads(&gamepads);
+        for i in 0..PINPUT_MAX_GAMEPADS {
+            assert_eq!(gpio[i * PINPUT_GAMEPAD_SIZE + offsets::BATTERY], i as u8);
+            assert_eq!(
+                gpio[i * PINPUT_GAMEPAD_SIZE + offsets::HI_FREQ_RUMBLE],
+                0x80 | i as u8
+            );
+        }
+        assert_eq!(encode_gamepads(&decode_gamepads(&gpio)), gpio);
+    }
+
+    #[test]
+    fn magic_matches_pinput_lua() {
+        // `pi_magic` in `pinput.lua` is written with `poke4`, which is little-endian.
+        let pi_magic: [u32; 4] = [0x46c72002, 0x6e44ab77, 0xd67fdcbe, 0x4d9877d2];
+        let mut expected = [0u8; 16];
+        for (chunk, word) in expected.chunks_exact_mut(4).zip(pi_magic) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        assert_eq!(PINPUT_MAGIC, expected);
+    }
+
+    #[test]
+    fn magic_write_and_check() {
+        let mut gpio = [0u8; PINPUT_GPIO_SIZE];
+        assert!(!has_magic(&gpio));
+        write_magic(&mut gpio);
+        assert!(has_magic(&gpio));
+        assert!(gpio[PINPUT_MAGIC.len()..].iter().all(|b| *b == 0));
+    }
+}
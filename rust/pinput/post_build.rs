@@ -45,7 +45,18 @@ fn main() -> Result<()> {
 
     match env::consts::OS {
         "macos" => macos_codesign(executable_path.as_path()),
-        "linux" => linux_setcap(executable_path.as_path()),
+        // Only the memory helper needs the capability; pinput itself runs unprivileged.
+        // It's a separate crate, so that it doesn't link SDL, and has to be built first.
+        "linux" => {
+            let helper_path = crate_out_dir.join("pinput-helper");
+            if !helper_path.exists() {
+                return Err(anyhow!(
+                    "Build pinput-helper into the same target directory first: \
+                     cargo build --manifest-path ../pinput-helper/Cargo.toml --target-dir target"
+                ));
+            }
+            linux_setcap(helper_path.as_path())
+        }
         "windows" => windows_mask(executable_path.as_path()),
         _ => Ok(()),
     }
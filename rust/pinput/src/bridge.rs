@@ -0,0 +1,849 @@
+//! Embeddable Pinput: owns the input sources and runtime connections,
+//! and syncs them one frame at a time.
+
+use pinput_protocol::{encode_gamepads, PinputGamepadArray, PINPUT_GPIO_SIZE, PINPUT_MAX_GAMEPADS};
+use process_memory::Pid;
+use sdl2::controller::Button;
+use sdl2::event::Event;
+use sdl2::{EventPump, GameControllerSubsystem, JoystickSubsystem, Sdl};
+#[cfg(feature = "haptics")]
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::constants::{DEFAULT_SCAN_BUDGET, FRAME_DURATION_MS, SCAN_INTERVAL_MS};
+use crate::error::Error;
+use crate::gamepad::SdlGamepad;
+use crate::gamepad_source::{GamepadSource, RumbleMix};
+use crate::gpio_backend::{host_bytes_match, sync_gpio, GpioSync, WriteRate};
+#[cfg(feature = "haptics")]
+use crate::haptic_subsystem::HapticSubsystem;
+use crate::magic_scanner::MagicScanner;
+#[cfg(target_os = "linux")]
+use crate::memory_helper::HelperClient;
+use crate::runtime_connection::{
+    process_start_time, Error as RuntimeConnectionError, FoundRuntime, MemoryAccess,
+    RuntimeConnection,
+};
+use crate::runtime_definition::{load_runtime_definitions, RuntimeDefinition};
+use crate::runtime_policy::RuntimePolicy;
+use crate::slots::{SlotMemory, Slots};
+
+/// Settings for creating a [`Bridge`].
+#[derive(Debug, Default, Clone)]
+pub struct BridgeOptions {
+    /// Websocket URL of a Buttplug server to use instead of the in-process one.
+    #[cfg(feature = "haptics")]
+    pub haptics_server: Option<String>,
+    /// File to remember which controller sat in which slot across runs.
+    /// Without one, controllers only get their slots back until the bridge is dropped.
+    pub slot_file: Option<PathBuf>,
+    /// Don't give new controllers a slot until someone presses Start or A on them,
+    /// so that slots fill up in the order players pick up their controllers.
+    pub press_to_join: bool,
+    /// Only connect to this process, instead of the first runtime we find.
+    pub pid: Option<Pid>,
+    /// Where the GPIO block is in that process, if already known.
+    /// Ignored without a PID.
+    pub gpio_address: Option<usize>,
+    /// Which controllers each newly connected runtime gets.
+    pub runtime_policy: RuntimePolicy,
+    /// Config file describing more runtimes, which are tried before the built-in ones.
+    pub runtime_file: Option<PathBuf>,
+    /// Most bytes of runtime memory to search for Pinput magic per scan.
+    /// Defaults to [`DEFAULT_SCAN_BUDGET`].
+    pub scan_budget: Option<usize>,
+    /// Helper executable to read and write runtime memory through,
+    /// so that only it needs the privileges to. It ignores `runtime_file`, and only searches
+    /// runtimes that are built in or described in root's
+    /// [`SYSTEM_RUNTIME_FILE`](crate::memory_helper::SYSTEM_RUNTIME_FILE).
+    #[cfg(target_os = "linux")]
+    pub memory_helper: Option<PathBuf>,
+}
+
+/// What a scan found, one result for each runtime it looked at.
+type ScanResults = Vec<Result<FoundRuntime, RuntimeConnectionError>>;
+
+/// Called with the runtime that was just connected or disconnected.
+pub type ConnectionCallback = Box<dyn FnMut(&RuntimeConnection)>;
+
+/// A live connection and the rule for which controllers it gets.
+struct Runtime {
+    connection: RuntimeConnection,
+    policy: RuntimePolicy,
+    /// The GPIO block as we last wrote it, to tell if something else has written over it since.
+    last_written: Option<[u8; PINPUT_GPIO_SIZE]>,
+    /// How many times the cartridge has written magic since we connected.
+    handshakes: u64,
+    /// Set while the GPIO block has gone missing and we're looking for it again.
+    relocating: bool,
+    /// Result of a search for the GPIO block running in the background, if there is one.
+    search: Option<Receiver<Result<usize, RuntimeConnectionError>>>,
+}
+
+impl Runtime {
+    fn new(connection: RuntimeConnection, policy: RuntimePolicy) -> Self {
+        Runtime {
+            connection,
+            policy,
+            last_written: None,
+            handshakes: 0,
+            relocating: false,
+            search: None,
+        }
+    }
+}
+
+/// Connects gamepads and haptic devices to any number of runtimes.
+/// Call [`Bridge::scan`] now and then to pick up new runtimes and [`Bridge::tick`] once per frame,
+/// or use [`Bridge::run`] to do both on timers.
+/// Must be created and used from within a Tokio runtime.
+pub struct Bridge {
+    /// Keeps SDL initialized for as long as the bridge exists.
+    _sdl_context: Sdl,
+    joystick_subsystem: JoystickSubsystem,
+    game_controller_subsystem: GameControllerSubsystem,
+    /// Tells us when game controllers are plugged in or unplugged.
+    event_pump: EventPump,
+    #[cfg(feature = "haptics")]
+    haptic_subsystem: HapticSubsystem,
+    sdl_gamepads: Slots<SdlGamepad>,
+    slot_memory: SlotMemory,
+    press_to_join: bool,
+    /// Open controllers waiting for a press to join, in press-to-join mode.
+    waiting_sdl_gamepads: Vec<SdlGamepad>,
+    pid: Option<Pid>,
+    gpio_address: Option<usize>,
+    runtime_policy: RuntimePolicy,
+    /// Kinds of runtime to look for, in the order they're tried.
+    runtime_definitions: Vec<Arc<RuntimeDefinition>>,
+    /// How we get into runtime memory, including how far we've searched runtimes
+    /// that haven't written magic yet. Locked by the scan running in the background,
+    /// so only touch it while there isn't one.
+    memory_access: Arc<Mutex<MemoryAccess>>,
+    /// Results of the scan running in the background, if there is one.
+    scan: Option<Receiver<ScanResults>>,
+    /// Runtimes whose memory we weren't allowed into, which we've stopped trying,
+    /// with when each one started, in case its PID is reused.
+    denied_runtimes: Vec<(Pid, u64)>,
+    /// Which slot each haptic device sits in, keyed by Buttplug device index.
+    /// They take slots that gamepads aren't using, and move when a gamepad needs theirs.
+    #[cfg(feature = "haptics")]
+    haptic_slots: BTreeMap<u32, usize>,
+    /// Live connections, in the order they were made.
+    runtimes: Vec<Runtime>,
+    /// How much we're writing to runtimes, summed over all of them.
+    write_rate: WriteRate,
+    on_connect: Option<ConnectionCallback>,
+    on_disconnect: Option<ConnectionCallback>,
+    on_cart_restart: Option<ConnectionCallback>,
+}
+
+impl Bridge {
+    pub async fn new(options: BridgeOptions) -> Result<Self, Error> {
+        // Using the Windows raw input joystick driver breaks XInput devices on Windows.
+        // https://github.com/MysteriousJ/Joystick-Input-Examples#rawinput suggests that raw input
+        // needs a window to function, which we don't currently create.
+        sdl2::hint::set("SDL_JOYSTICK_RAWINPUT", "0");
+
+        let sdl_context = sdl2::init().map_err(Error::SdlStringError)?;
+        let joystick_subsystem = sdl_context.joystick().map_err(Error::SdlStringError)?;
+        let game_controller_subsystem = sdl_context
+            .game_controller()
+            .map_err(Error::SdlStringError)?;
+        let event_pump = sdl_context.event_pump().map_err(Error::SdlStringError)?;
+        #[cfg(feature = "haptics")]
+        let haptic_subsystem = HapticSubsystem::new(options.haptics_server).await?;
+
+        let scan_budget = options.scan_budget.unwrap_or(DEFAULT_SCAN_BUDGET);
+        #[cfg(target_os = "linux")]
+        let memory_access = match &options.memory_helper {
+            Some(helper) => {
+                MemoryAccess::Helper(Arc::new(HelperClient::spawn(helper, scan_budget)?))
+            }
+            None => MemoryAccess::Direct(MagicScanner::new(scan_budget)),
+        };
+        #[cfg(not(target_os = "linux"))]
+        let memory_access = MemoryAccess::Direct(MagicScanner::new(scan_budget));
+
+        Ok(Self {
+            _sdl_context: sdl_context,
+            joystick_subsystem,
+            game_controller_subsystem,
+            event_pump,
+            #[cfg(feature = "haptics")]
+            haptic_subsystem,
+            sdl_gamepads: Default::default(),
+            slot_memory: SlotMemory::load(options.slot_file),
+            press_to_join: options.press_to_join,
+            waiting_sdl_gamepads: Vec::new(),
+            pid: options.pid,
+            gpio_address: options.gpio_address,
+            runtime_policy: options.runtime_policy,
+            runtime_definitions: load_runtime_definitions(options.runtime_file.as_deref()),
+            memory_access: Arc::new(Mutex::new(memory_access)),
+            scan: None,
+            denied_runtimes: Vec::new(),
+            #[cfg(feature = "haptics")]
+            haptic_slots: BTreeMap::new(),
+            runtimes: Vec::new(),
+            write_rate: WriteRate::new(Instant::now()),
+            on_connect: None,
+            on_disconnect: None,
+            on_cart_restart: None,
+        })
+    }
+
+    /// Set a function to call when we connect to a runtime.
+    pub fn on_connect(&mut self, callback: impl FnMut(&RuntimeConnection) + 'static) {
+        self.on_connect = Some(Box::new(callback));
+    }
+
+    /// Set a function to call when a runtime goes away or the bridge shuts down.
+    pub fn on_disconnect(&mut self, callback: impl FnMut(&RuntimeConnection) + 'static) {
+        self.on_disconnect = Some(Box::new(callback));
+    }
+
+    /// Set a function to call when a connected runtime's cartridge is re-run or reloaded,
+    /// and writes Pinput magic again.
+    pub fn on_cart_restart(&mut self, callback: impl FnMut(&RuntimeConnection) + 'static) {
+        self.on_cart_restart = Some(Box::new(callback));
+    }
+
+    /// Bytes written to all runtimes' GPIO blocks per second, over the last second or so.
+    /// Slots that didn't change since the last frame aren't written, so this is zero when idle.
+    pub fn gpio_bytes_per_second(&self) -> usize {
+        self.write_rate.per_second()
+    }
+
+    /// The runtimes we're currently connected to, in the order they connected.
+    pub fn runtime_connections(&self) -> impl Iterator<Item = &RuntimeConnection> {
+        self.runtimes.iter().map(|runtime| &runtime.connection)
+    }
+
+    /// Change which controllers an already connected runtime gets.
+    /// Returns `false` if we're not connected to that process.
+    pub fn set_runtime_policy(&mut self, pid: Pid, policy: RuntimePolicy) -> bool {
+        match self
+            .runtimes
+            .iter_mut()
+            .find(|runtime| runtime.connection.pid == pid)
+        {
+            Some(runtime) => {
+                runtime.policy = policy;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Run one frame: sync gamepads with every connected runtime,
+    /// after connecting to any that the last scan found.
+    pub fn tick(&mut self) -> Result<(), Error> {
+        // Also updates the state of all game controllers.
+        self.handle_sdl_events();
+        self.poll_scan();
+
+        #[cfg(feature = "haptics")]
+        {
+            self.haptic_subsystem.handle_events();
+            self.place_haptic_devices();
+        }
+
+        let mut result = Ok(());
+        let shares = self
+            .runtimes
+            .iter()
+            .filter(|runtime| runtime.policy == RuntimePolicy::SplitBySlot)
+            .count();
+        let mut share = 0;
+        let mut bytes_written = 0;
+        // Sources rumble once all the runtimes sharing them have had their say.
+        let rumble = RumbleMix::default();
+        for mut runtime in std::mem::take(&mut self.runtimes) {
+            let runtime_share = share;
+            if runtime.policy == RuntimePolicy::SplitBySlot {
+                share += 1;
+            }
+            if runtime.relocating {
+                // Not synced until we know where its GPIO block went.
+                if self.poll_relocation(&mut runtime) {
+                    self.runtimes.push(runtime);
+                } else {
+                    self.disconnected(&runtime.connection);
+                }
+                continue;
+            }
+
+            // Keep going on error, so that runtimes we haven't synced yet aren't dropped.
+            match self.sync_runtime(&mut runtime, runtime_share, shares, &rumble) {
+                Ok(Some(sync)) => {
+                    runtime.last_written = Some(sync.written);
+                    bytes_written += sync.bytes_written;
+                    if sync.handshake {
+                        runtime.handshakes += 1;
+                        if runtime.handshakes > 1 {
+                            if let Some(on_cart_restart) = &mut self.on_cart_restart {
+                                on_cart_restart(&runtime.connection);
+                            }
+                        }
+                    }
+                    self.runtimes.push(runtime);
+                }
+                Ok(None) => self.disconnected(&runtime.connection),
+                Err(err) => {
+                    self.runtimes.push(runtime);
+                    result = Err(err);
+                }
+            }
+        }
+        self.write_rate.record(bytes_written, Instant::now());
+        if let Err(err) = rumble.apply(self.slot_sources()) {
+            result = Err(err);
+        }
+
+        result
+    }
+
+    /// Call [`Bridge::tick`] every frame, and [`Bridge::scan`] every scan interval.
+    /// Only returns on error. Drop the future to stop, then call [`Bridge::shutdown`].
+    pub async fn run(&mut self) -> Result<(), Error> {
+        let mut frame_interval = interval(Duration::from_millis(FRAME_DURATION_MS));
+        frame_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut scan_interval = interval(Duration::from_millis(SCAN_INTERVAL_MS));
+        scan_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = frame_interval.tick() => self.tick()?,
+                _ = scan_interval.tick() => self.scan(),
+            }
+        }
+    }
+
+    /// Tell the runtime that all gamepads are gone, stop any rumble, and release SDL.
+    pub fn shutdown(mut self) {
+        for sdl_gamepad in self.sdl_gamepads.iter_mut().flatten() {
+            if sdl_gamepad.has_rumble {
+                let _ = sdl_gamepad.game_controller.set_rumble(0, 0, 0);
+            }
+        }
+
+        for mut runtime in std::mem::take(&mut self.runtimes) {
+            // The runtime may already be gone, in which case there's nothing to clean up.
+            let _ = runtime
+                .connection
+                .gpio
+                .write(&encode_gamepads(&PinputGamepadArray::default()));
+            self.disconnected(&runtime.connection);
+        }
+    }
+
+    /// Check that connected runtimes' GPIO blocks are still where we left them,
+    /// and start looking for runtimes with Pinput magic that we aren't connected to yet.
+    /// Searching reads a lot of memory, so it runs in the background,
+    /// and [`Bridge::tick`] connects to whatever it finds. Does nothing until the last scan is done.
+    pub fn scan(&mut self) {
+        if self.scan.is_some() {
+            return;
+        }
+
+        for mut runtime in std::mem::take(&mut self.runtimes) {
+            if self.validate_runtime(&mut runtime) {
+                self.runtimes.push(runtime);
+            } else {
+                self.disconnected(&runtime.connection);
+            }
+        }
+
+        // A denied runtime's PID may belong to another process by now, which gets its own try.
+        self.denied_runtimes
+            .retain(|&(pid, started)| process_start_time(pid) == Some(started));
+        let skip: Vec<Pid> = self
+            .runtime_connections()
+            .map(|runtime_connection| runtime_connection.pid)
+            .chain(self.denied_runtimes.iter().map(|&(pid, _)| pid))
+            .collect();
+        let pid = match self.pid {
+            Some(pid) if skip.contains(&pid) => return,
+            pid => pid,
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let definitions = self.runtime_definitions.clone();
+        let gpio_address = self.gpio_address;
+        let memory_access = self.memory_access.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut access = memory_access
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let results = match pid {
+                Some(pid) => vec![RuntimeConnection::find(
+                    &definitions,
+                    pid,
+                    gpio_address,
+                    &mut access,
+                )],
+                None => RuntimeConnection::find_all(&definitions, &skip, &mut access),
+            };
+            drop(access);
+            // The bridge may have been dropped by the time we're done.
+            let _ = sender.send(results);
+        });
+        self.scan = Some(receiver);
+    }
+
+    /// Connect to the runtimes that the background scan found, if it's done.
+    fn poll_scan(&mut self) {
+        let Some(scan) = &self.scan else {
+            return;
+        };
+        let mut results = match scan.try_recv() {
+            Ok(results) => results,
+            Err(TryRecvError::Empty) => return,
+            // The scan gave up without saying why. Try again at the next scan.
+            Err(TryRecvError::Disconnected) => vec![],
+        };
+        self.scan = None;
+        let connected = !self.runtimes.is_empty();
+        if results.is_empty() && !connected {
+            results.push(Err(RuntimeConnectionError::NoProcessesFound));
+        }
+
+        for result in results {
+            let result = result.and_then(|found| {
+                let access = self
+                    .memory_access
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                RuntimeConnection::open(found, &access)
+            });
+            match result {
+                Ok(runtime_connection) => {
+                    if let Some(on_connect) = &mut self.on_connect {
+                        on_connect(&runtime_connection);
+                    }
+                    self.runtimes
+                        .push(Runtime::new(runtime_connection, self.runtime_policy));
+                }
+                // Retrying won't help, so say what will, even while other runtimes are playing.
+                Err(err @ RuntimeConnectionError::AccessDenied { pid, .. }) => {
+                    println!("Failed to connect to a runtime: {err}");
+                    if let Some(started) = process_start_time(pid) {
+                        self.denied_runtimes.push((pid, started));
+                    }
+                }
+                // Runtimes that haven't enabled Pinput yet would be noisy while others are playing.
+                Err(err) if !connected => {
+                    println!("Failed to connect to a runtime: {:#?}", err)
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Open and close SDL game controllers as they're plugged in and unplugged.
+    /// SDL also sends an added event for each controller that's already plugged in at startup.
+    fn handle_sdl_events(&mut self) {
+        let events: Vec<Event> = self.event_pump.poll_iter().collect();
+        for event in events {
+            match event {
+                Event::ControllerDeviceAdded { which, .. } => self.controller_added(which),
+                Event::ControllerDeviceRemoved { which, .. } => self.controller_removed(which),
+                Event::ControllerButtonDown {
+                    which,
+                    button: Button::Start | Button::A,
+                    ..
+                } => self.controller_joined(which),
+                Event::ControllerButtonDown {
+                    which,
+                    button:
+                        button @ (Button::DPadUp
+                        | Button::DPadDown
+                        | Button::DPadLeft
+                        | Button::DPadRight),
+                    ..
+                } => self.slot_chord(which, button),
+                _ => {}
+            }
+        }
+    }
+
+    /// `device_index` is only valid until the next event, so we open the controller right away.
+    fn controller_added(&mut self, device_index: u32) {
+        let sdl_gamepad = match open_sdl_gamepad(
+            &self.joystick_subsystem,
+            &self.game_controller_subsystem,
+            device_index,
+        ) {
+            Ok(sdl_gamepad) => sdl_gamepad,
+            Err(err) => {
+                println!("Failed to open game controller {device_index}: {err}");
+                return;
+            }
+        };
+
+        let instance_id = sdl_gamepad.game_controller.instance_id();
+        if let Some(slot) = self
+            .sdl_gamepads
+            .position(|other| other.game_controller.instance_id() == instance_id)
+        {
+            // Already open. Dropping the new handle just releases SDL's extra reference.
+            println!("{} is already in slot {slot}.", sdl_gamepad.name());
+            return;
+        }
+        if self
+            .waiting_sdl_gamepads
+            .iter()
+            .any(|other| other.game_controller.instance_id() == instance_id)
+        {
+            return;
+        }
+
+        if self.press_to_join {
+            println!(
+                "{} connected. Press Start or A to join.",
+                sdl_gamepad.name()
+            );
+            self.waiting_sdl_gamepads.push(sdl_gamepad);
+        } else {
+            let controller_id = sdl_gamepad.controller_id();
+            let preferred_slot = self
+                .slot_memory
+                .preferred_slot(&controller_id, &self.sdl_gamepads);
+            // Full slots drop the gamepad, which closes it.
+            let _ = self.assign_slot(sdl_gamepad, preferred_slot);
+        }
+    }
+
+    /// In press-to-join mode, give a waiting controller the lowest free slot.
+    fn controller_joined(&mut self, instance_id: u32) {
+        let Some(index) = self
+            .waiting_sdl_gamepads
+            .iter()
+            .position(|sdl_gamepad| sdl_gamepad.game_controller.instance_id() == instance_id)
+        else {
+            return;
+        };
+        let sdl_gamepad = self.waiting_sdl_gamepads.remove(index);
+        if let Err(sdl_gamepad) = self.assign_slot(sdl_gamepad, None) {
+            // Let it try again once someone leaves.
+            self.waiting_sdl_gamepads.push(sdl_gamepad);
+        }
+    }
+
+    /// Put a gamepad in the preferred slot if it's free, otherwise the lowest free slot,
+    /// and remember where it went. Hands the gamepad back if there's no room.
+    fn assign_slot(
+        &mut self,
+        sdl_gamepad: SdlGamepad,
+        preferred_slot: Option<usize>,
+    ) -> Result<usize, SdlGamepad> {
+        let name = sdl_gamepad.name();
+        let controller_id = sdl_gamepad.controller_id();
+        match self
+            .sdl_gamepads
+            .insert_preferring(sdl_gamepad, preferred_slot)
+        {
+            Ok(slot) => {
+                println!("{name} connected to slot {slot}.");
+                self.slot_memory.remember(controller_id, slot, None);
+                Ok(slot)
+            }
+            Err(sdl_gamepad) => {
+                println!("{name} connected, but all slots are taken.");
+                Err(sdl_gamepad)
+            }
+        }
+    }
+
+    /// Guide + left or right moves a controller to the previous or next free slot.
+    /// Guide + up or down swaps it with whatever's in the previous or next slot, if anything.
+    fn slot_chord(&mut self, instance_id: u32, button: Button) {
+        let Some(slot) = self
+            .sdl_gamepads
+            .position(|sdl_gamepad| sdl_gamepad.game_controller.instance_id() == instance_id)
+        else {
+            return;
+        };
+        let Some(sdl_gamepad) = self.sdl_gamepads.get_mut(slot) else {
+            return;
+        };
+        if !sdl_gamepad.game_controller.button(Button::Guide) {
+            return;
+        }
+        sdl_gamepad.guide_chord.start();
+
+        let target = match button {
+            Button::DPadLeft => (0..slot).rev().find(|&target| self.slot_is_free(target)),
+            Button::DPadRight => {
+                (slot + 1..PINPUT_MAX_GAMEPADS).find(|&target| self.slot_is_free(target))
+            }
+            Button::DPadUp => slot.checked_sub(1),
+            _ => Some(slot + 1).filter(|&target| target < PINPUT_MAX_GAMEPADS),
+        };
+        let Some(target) = target else {
+            return;
+        };
+
+        self.sdl_gamepads.swap(slot, target);
+        #[cfg(feature = "haptics")]
+        for haptic_slot in self.haptic_slots.values_mut() {
+            if *haptic_slot == target {
+                println!("Haptic device moved from slot {target} to slot {slot}.");
+                *haptic_slot = slot;
+            }
+        }
+        for (from, to) in [(slot, target), (target, slot)] {
+            match self.sdl_gamepads.get(to) {
+                Some(sdl_gamepad) => {
+                    println!(
+                        "{} moved from slot {from} to slot {to}.",
+                        sdl_gamepad.name()
+                    );
+                    self.slot_memory
+                        .remember(sdl_gamepad.controller_id(), to, Some(from));
+                }
+                None => self.slot_memory.forget(to),
+            }
+        }
+    }
+
+    /// Is this slot empty of both gamepads and haptic devices?
+    fn slot_is_free(&self, slot: usize) -> bool {
+        #[cfg(feature = "haptics")]
+        if self
+            .haptic_slots
+            .values()
+            .any(|&haptic_slot| haptic_slot == slot)
+        {
+            return false;
+        }
+        self.sdl_gamepads.is_free(slot)
+    }
+
+    /// Give new haptic devices the lowest free slot, forget ones that have gone away,
+    /// and move any whose slot a gamepad has taken.
+    #[cfg(feature = "haptics")]
+    fn place_haptic_devices(&mut self) {
+        let indices: Vec<u32> = self.haptic_subsystem.device_indices().collect();
+        self.haptic_slots.retain(|index, _| indices.contains(index));
+        for index in indices {
+            match self.haptic_slots.get(&index) {
+                Some(&slot) if self.sdl_gamepads.is_free(slot) => continue,
+                Some(_) => {
+                    self.haptic_slots.remove(&index);
+                }
+                None => {}
+            }
+            // If there's no room, try again next frame.
+            if let Some(slot) = (0..PINPUT_MAX_GAMEPADS).find(|&slot| self.slot_is_free(slot)) {
+                println!("Haptic device {index} is in slot {slot}.");
+                self.haptic_slots.insert(index, slot);
+            }
+        }
+    }
+
+    fn controller_removed(&mut self, instance_id: u32) {
+        // Dropping the gamepad closes it.
+        if let Some((slot, sdl_gamepad)) = self
+            .sdl_gamepads
+            .remove_by(|sdl_gamepad| sdl_gamepad.game_controller.instance_id() == instance_id)
+        {
+            println!("{} disconnected from slot {slot}.", sdl_gamepad.name());
+        }
+        self.waiting_sdl_gamepads
+            .retain(|sdl_gamepad| sdl_gamepad.game_controller.instance_id() != instance_id);
+    }
+
+    fn disconnected(&mut self, runtime_connection: &RuntimeConnection) {
+        if let Some(on_disconnect) = &mut self.on_disconnect {
+            on_disconnect(runtime_connection);
+        }
+    }
+
+    /// Make sure a runtime's GPIO block is still mapped and still holds what we last wrote,
+    /// or fresh magic from a restarted cartridge. If not, the runtime may have moved it,
+    /// so start searching for it again in the background.
+    /// Returns `false` if the runtime has gone away.
+    fn validate_runtime(&mut self, runtime: &mut Runtime) -> bool {
+        if !runtime.relocating {
+            let connection = &mut runtime.connection;
+            let problem = match connection.is_mapped() {
+                Ok(false) => Some("is no longer mapped"),
+                // Can't list the runtime's mappings because it's gone.
+                Err(_) => return false,
+                Ok(true) => match connection.gpio.has_magic() {
+                    Ok(true) => None,
+                    // The next frame will notice that it's gone.
+                    Err(_) => None,
+                    Ok(false) => match (connection.gpio.read(), runtime.last_written) {
+                        (Ok(gpio), Some(last_written))
+                            if !host_bytes_match(&gpio, &last_written) =>
+                        {
+                            Some("was overwritten")
+                        }
+                        _ => None,
+                    },
+                },
+            };
+            let Some(problem) = problem else {
+                return true;
+            };
+            if connection.gpio_address.is_none() {
+                // Nowhere else to look for it.
+                return false;
+            }
+            println!(
+                "{} GPIO block in PID {} {problem}; searching for it again.",
+                connection.flavor, connection.pid
+            );
+            runtime.relocating = true;
+            runtime.last_written = None;
+        }
+
+        if runtime.search.is_none() {
+            let (sender, receiver) = mpsc::channel();
+            let relocate = self
+                .memory_access
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .relocator(&runtime.connection);
+            tokio::task::spawn_blocking(move || {
+                // The bridge may have given up on this runtime by the time we're done.
+                let _ = sender.send(relocate());
+            });
+            runtime.search = Some(receiver);
+        }
+        true
+    }
+
+    /// Pick up the result of a background search for a runtime's GPIO block, if it's done.
+    /// Returns `false` if the runtime has gone away.
+    fn poll_relocation(&mut self, runtime: &mut Runtime) -> bool {
+        let Some(search) = &runtime.search else {
+            return true;
+        };
+        let result = match search.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return true,
+            // The search gave up without saying why. Try again at the next scan.
+            Err(TryRecvError::Disconnected) => {
+                runtime.search = None;
+                return true;
+            }
+        };
+        runtime.search = None;
+
+        let connection = &mut runtime.connection;
+        match result.and_then(|gpio_address| {
+            connection.relocate(gpio_address)?;
+            Ok(gpio_address)
+        }) {
+            Ok(gpio_address) => {
+                println!(
+                    "Found {} GPIO block in PID {} at {gpio_address:#x}.",
+                    connection.flavor, connection.pid
+                );
+                runtime.relocating = false;
+                true
+            }
+            // Try again at the next scan, once the cartridge has written magic.
+            Err(RuntimeConnectionError::PinputNotEnabled { .. }) => true,
+            Err(err) => {
+                println!("Failed to find {} again: {err}", connection.flavor);
+                false
+            }
+        }
+    }
+
+    /// The source in each slot. Gamepads and haptic devices keep the slots they were given
+    /// until they move.
+    fn slot_sources(&mut self) -> Vec<Option<&mut dyn GamepadSource>> {
+        #[cfg_attr(not(feature = "haptics"), allow(unused_mut))]
+        let mut sources: Vec<Option<&mut dyn GamepadSource>> = self
+            .sdl_gamepads
+            .iter_mut()
+            .map(|sdl_gamepad| {
+                sdl_gamepad
+                    .as_mut()
+                    .map(|sdl_gamepad| sdl_gamepad as &mut dyn GamepadSource)
+            })
+            .collect();
+        #[cfg(feature = "haptics")]
+        for (index, haptic_device) in self.haptic_subsystem.devices_mut() {
+            if let Some(&slot) = self.haptic_slots.get(&index) {
+                sources[slot] = Some(haptic_device);
+            }
+        }
+        sources
+    }
+
+    /// Sync SDL gamepads with the runtime, adding the rumble it asks for to `rumble`.
+    /// Returns `None` if the runtime has gone away.
+    /// `share` and `shares` say which part of the slots this runtime gets if it's splitting them.
+    fn sync_runtime(
+        &mut self,
+        runtime: &mut Runtime,
+        share: usize,
+        shares: usize,
+        rumble: &RumbleMix,
+    ) -> Result<Option<GpioSync>, Error> {
+        let mut sources = rumble.sources(self.slot_sources());
+        let sources = runtime.policy.select(
+            sources.iter_mut().map(|source| {
+                source
+                    .as_mut()
+                    .map(|source| source as &mut dyn GamepadSource)
+            }),
+            share,
+            shares,
+        );
+
+        let runtime_connection = &mut runtime.connection;
+        match sync_gpio(
+            runtime_connection.gpio.as_mut(),
+            runtime.last_written.as_ref(),
+            sources,
+        ) {
+            Ok(sync) => Ok(Some(sync)),
+            Err(Error::Gpio(err)) => {
+                // Failure here probably indicates that the runtime quit.
+                println!("Failed to access {}: {:#}", runtime_connection.flavor, err);
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+fn open_sdl_gamepad(
+    joystick_subsystem: &JoystickSubsystem,
+    game_controller_subsystem: &GameControllerSubsystem,
+    sdl_gamepad_index: u32,
+) -> Result<SdlGamepad, Error> {
+    let mut game_controller = game_controller_subsystem.open(sdl_gamepad_index)?;
+
+    // There's no way to test for rumble support other than by trying it.
+    // TODO: this will be fixed in SDL 2.0.18, with `SDL_GameControllerHasRumble`.
+    let has_rumble = game_controller.set_rumble(1, 1, 0).is_ok();
+    println!(
+        "{} {} rumble.",
+        game_controller.name(),
+        if has_rumble {
+            "supports"
+        } else {
+            "doesn't support"
+        }
+    );
+
+    Ok(SdlGamepad {
+        joystick: joystick_subsystem.open(sdl_gamepad_index)?,
+        game_controller,
+        has_rumble,
+        guide_chord: Default::default(),
+    })
+}
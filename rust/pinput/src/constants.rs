@@ -1,13 +1,20 @@
-use uuid::Uuid;
-
-/// Magic byte sequence we used to identify Pinput-enabled cartridges.
-pub const PINPUT_MAGIC: Uuid = Uuid::from_u128(0x0220c74677ab446ebedc7fd6d277984d);
+/// 60 Hz.
+pub static FRAME_DURATION_MS: u64 = 16;
 
-/// Pinput can fit this many gamepads into the GPIO area.
-pub const PINPUT_MAX_GAMEPADS: usize = 8;
+/// How long Guide is kept from the cartridge after it's pressed,
+/// in case a D-pad press turns it into a slot-changing chord.
+pub static CHORD_WINDOW_MS: u64 = 250;
 
-/// 60 Hz.
-pub static FRAME_DURATION_MS: i64 = 16;
+/// How long the cartridge sees a Guide tap that we held back and then let through.
+/// Long enough for a 30 FPS cartridge to catch it.
+pub static GUIDE_TAP_MS: u64 = 50;
 
 /// 1 Hz between attempts to connect to the runtime.
-pub static SCAN_INTERVAL_MS: i64 = 1000;
+pub static SCAN_INTERVAL_MS: u64 = 1000;
+
+/// How much runtime memory to copy at a time while searching for Pinput magic.
+pub static SCAN_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How much runtime memory to search for Pinput magic per scan, by default.
+/// Big mappings get searched a piece at a time over several scans.
+pub static DEFAULT_SCAN_BUDGET: usize = 16 * 1024 * 1024;
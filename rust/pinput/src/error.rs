@@ -1,28 +1,30 @@
+use std::path::PathBuf;
+
 use crate::runtime_connection;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
+    #[cfg(feature = "bridge")]
     #[error("SDL error: {0}")]
     SdlStringError(String),
 
+    #[cfg(feature = "bridge")]
     #[error("SDL error: {0}")]
     SdlError(#[from] sdl2::IntegerOrSdlError),
 
     #[error("Runtime connection error")]
     RuntimeConnection(#[from] runtime_connection::Error),
 
-    #[error("Ctrl-C handler error")]
-    CtrlC(#[from] ctrlc::Error),
-
-    #[error("Killed by Ctrl-C")]
-    KilledByCtrlC,
-
-    #[error("Channel error")]
-    RecvError(#[from] std::sync::mpsc::RecvError),
-
     #[error("I/O error")]
     IOError(#[from] std::io::Error),
 
+    /// Reading or writing runtime GPIO failed, which usually means the runtime quit.
+    #[error("GPIO access error: {0}")]
+    Gpio(#[source] std::io::Error),
+
+    #[error("don't know which runtime runs {0}")]
+    UnknownCartType(PathBuf),
+
     #[cfg(target_os = "linux")]
     #[error("Missing prerequisites")]
     MissingPrerequisites,
@@ -31,7 +33,15 @@ pub enum Error {
     #[error("Capabilities error")]
     CapsError(#[from] caps::errors::CapsError),
 
+    /// Boxed because it's much larger than our other errors.
     #[cfg(feature = "haptics")]
     #[error("Buttplug client error")]
-    ButtplugClientError(#[from] buttplug::client::ButtplugClientError),
+    ButtplugClientError(Box<buttplug::client::ButtplugClientError>),
+}
+
+#[cfg(feature = "haptics")]
+impl From<buttplug::client::ButtplugClientError> for Error {
+    fn from(err: buttplug::client::ButtplugClientError) -> Self {
+        Self::ButtplugClientError(Box::new(err))
+    }
 }
@@ -0,0 +1,407 @@
+//! Recognizes PICO-8 executables by what's inside them rather than by their file name,
+//! since exported standalone cartridges can be called anything.
+//!
+//! Linux doesn't have *any* kind of convenient executable metadata, and PICO-8 on Windows doesn't
+//! use the PE `VERSIONINFO` resource that is the closest equivalent of `Info.plist`,
+//! either in the regular PICO-8 binary or standalone cartridges.
+//! So we parse ELF and PE files ourselves and look for things only PICO-8 would contain.
+//! This works the same on every platform, so Windows binaries can be checked under Wine too.
+
+use memchr::memmem;
+use object::{Object, ObjectSection, ObjectSymbol, ReadCache, SectionKind};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, Metadata};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Symbol name prefixes used by the PICO-8 runtime's API functions.
+/// Only Linux builds have symbols left, and not always then.
+const PICO8_SYMBOL_PREFIXES: &[&[u8]] = &[b"_p8_", b"p8_"];
+
+/// Strings that all appear in the read-only data of both regular PICO-8 and exported cartridges,
+/// on every platform. Written backwards, so that Pinput doesn't contain them all itself
+/// and take its own process, with its copies of runtime RAM, for PICO-8.
+const PICO8_STRINGS_REVERSED: [&[u8]; 2] = [b"8-OCIP", b"elffolaxel"];
+
+fn pico8_strings() -> [Vec<u8>; PICO8_STRINGS_REVERSED.len()] {
+    PICO8_STRINGS_REVERSED.map(|string| string.iter().rev().copied().collect())
+}
+
+/// PICO-8 is a few megabytes. Don't page through browsers and the like looking for it.
+const MAX_EXECUTABLE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Does this ELF or PE file look like the PICO-8 runtime?
+/// Looks for PICO-8 API symbols if the binary has any left,
+/// then for PICO-8 strings in read-only data (`.rodata` for ELF, `.rdata` for PE).
+pub fn is_pico8_binary(file: File) -> io::Result<bool> {
+    let data = ReadCache::new(file);
+    let binary = match object::File::parse(&data) {
+        Ok(binary) => binary,
+        Err(_) => return Ok(false),
+    };
+    if !matches!(
+        binary.format(),
+        object::BinaryFormat::Elf | object::BinaryFormat::Pe
+    ) {
+        return Ok(false);
+    }
+
+    let has_pico8_symbol = binary
+        .symbols()
+        .chain(binary.dynamic_symbols())
+        .filter_map(|symbol| symbol.name_bytes().ok())
+        .any(|name| {
+            PICO8_SYMBOL_PREFIXES
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+        });
+    if has_pico8_symbol {
+        return Ok(true);
+    }
+
+    // The strings may be spread across several sections.
+    let needles = pico8_strings();
+    let mut found = [false; PICO8_STRINGS_REVERSED.len()];
+    for section in binary.sections().filter(|section| {
+        matches!(
+            section.kind(),
+            SectionKind::ReadOnlyData | SectionKind::ReadOnlyString
+        )
+    }) {
+        let contents = section
+            .data()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        for (found, needle) in found.iter_mut().zip(&needles) {
+            *found = *found || memmem::find(contents, needle).is_some();
+        }
+    }
+    Ok(found.iter().all(|&found| found))
+}
+
+/// Identifies one file: its device and inode where we have them.
+#[cfg(unix)]
+type FileId = (u64, u64);
+
+#[cfg(unix)]
+fn file_id(_path: &Path, metadata: &Metadata) -> FileId {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+/// Identifies one file: its path where there are no inodes.
+#[cfg(not(unix))]
+type FileId = PathBuf;
+
+#[cfg(not(unix))]
+fn file_id(path: &Path, _metadata: &Metadata) -> FileId {
+    path.to_path_buf()
+}
+
+/// Identifies one version of one file.
+/// The modification time and size catch executables that were replaced in place.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct FileKey {
+    id: FileId,
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+/// Identifies one run of one process: its PID and start time, since PIDs get reused.
+pub type ProcessKey = (u32, u64);
+
+/// Remembers which executables are PICO-8, so that each file is only parsed once
+/// no matter how many scans see it.
+#[derive(Debug, Default)]
+pub struct ExeInspector {
+    cache: HashMap<FileKey, bool>,
+    /// Which file each process we've seen is running, or why we couldn't tell,
+    /// so that executables we can't open aren't tried again on every scan.
+    /// Also the path, in case the process has since run another executable.
+    processes: HashMap<ProcessKey, (PathBuf, Result<FileKey, io::ErrorKind>)>,
+}
+
+impl ExeInspector {
+    pub fn is_pico8(&mut self, path: &Path) -> io::Result<bool> {
+        let key = self.inspect(path)?;
+        Ok(self.cache[&key])
+    }
+
+    /// [`ExeInspector::is_pico8`] for the executable a process is running.
+    /// Failures are remembered too, until the process exits.
+    pub fn is_process_pico8(&mut self, process: ProcessKey, path: &Path) -> io::Result<bool> {
+        let file = match self.processes.get(&process) {
+            Some((inspected, file)) if inspected == path => file.clone(),
+            _ => {
+                let file = self.inspect(path).map_err(|err| err.kind());
+                self.processes
+                    .insert(process, (path.to_path_buf(), file.clone()));
+                file
+            }
+        };
+        Ok(self.cache[&file?])
+    }
+
+    /// Forget processes that aren't `running` any more, and files that only they were running.
+    pub fn forget_exited(&mut self, running: &HashSet<ProcessKey>) {
+        self.processes
+            .retain(|process, _| running.contains(process));
+        let in_use: HashSet<&FileKey> = self
+            .processes
+            .values()
+            .filter_map(|(_, file)| file.as_ref().ok())
+            .collect();
+        self.cache.retain(|file, _| in_use.contains(file));
+    }
+
+    /// Parse the file at `path` unless we already have, and return its key in the cache.
+    fn inspect(&mut self, path: &Path) -> io::Result<FileKey> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let key = FileKey {
+            id: file_id(path, &metadata),
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+        };
+        if self.cache.contains_key(&key) {
+            return Ok(key);
+        }
+
+        let is_pico8 = metadata.is_file()
+            && metadata.len() <= MAX_EXECUTABLE_SIZE
+            && has_executable_magic(&mut file)?
+            && is_pico8_binary(file)?;
+        self.cache.insert(key.clone(), is_pico8);
+        Ok(key)
+    }
+}
+
+/// Cheap check for ELF or PE (which starts with a DOS header) before parsing the file.
+fn has_executable_magic(file: &mut File) -> io::Result<bool> {
+    let mut magic = [0u8; 4];
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == *b"\x7fELF" || magic.starts_with(b"MZ")),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Shared by every scan.
+static EXE_INSPECTOR: Mutex<Option<ExeInspector>> = Mutex::new(None);
+
+fn with_inspector<T>(f: impl FnOnce(&mut ExeInspector) -> T) -> T {
+    let mut inspector = EXE_INSPECTOR
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    f(inspector.get_or_insert_with(Default::default))
+}
+
+/// [`ExeInspector::is_pico8`] with a process-wide cache,
+/// or [`ExeInspector::is_process_pico8`] if we know which process is running `path`.
+pub fn is_pico8_exe(path: &Path, process: Option<ProcessKey>) -> io::Result<bool> {
+    with_inspector(|inspector| match process {
+        Some(process) => inspector.is_process_pico8(process, path),
+        None => inspector.is_pico8(path),
+    })
+}
+
+/// [`ExeInspector::forget_exited`] for the process-wide cache.
+pub fn forget_exited_processes(running: &HashSet<ProcessKey>) {
+    with_inspector(|inspector| inspector.forget_exited(running))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object::pe::{IMAGE_FILE_EXECUTABLE_IMAGE, IMAGE_FILE_MACHINE_AMD64};
+    use object::write::pe::{NtHeaders, Writer};
+    use object::write::{self, Symbol, SymbolSection};
+    use object::{Architecture, BinaryFormat, Endianness, SymbolFlags};
+    use object::{SymbolKind, SymbolScope};
+
+    fn elf(symbol: Option<&str>, rodata: &[u8]) -> Vec<u8> {
+        let mut obj =
+            write::Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+        let section = obj.add_section(Vec::new(), b".rodata".to_vec(), SectionKind::ReadOnlyData);
+        obj.append_section_data(section, rodata, 1);
+        if let Some(symbol) = symbol {
+            obj.add_symbol(Symbol {
+                name: symbol.as_bytes().to_vec(),
+                value: 0,
+                size: 0,
+                kind: SymbolKind::Text,
+                scope: SymbolScope::Dynamic,
+                weak: false,
+                section: SymbolSection::Section(section),
+                flags: SymbolFlags::None,
+            });
+        }
+        obj.write().unwrap()
+    }
+
+    /// Minimal 64-bit Windows executable with nothing but an `.rdata` section.
+    fn pe(rdata: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut writer = Writer::new(true, 0x1000, 0x200, &mut data);
+        writer.reserve_dos_header_and_stub();
+        writer.reserve_nt_headers(16);
+        writer.reserve_section_headers(1);
+        let rdata_range = writer.reserve_rdata_section(rdata.len() as u32);
+
+        writer.write_dos_header_and_stub().unwrap();
+        writer.write_nt_headers(NtHeaders {
+            machine: IMAGE_FILE_MACHINE_AMD64,
+            time_date_stamp: 0,
+            characteristics: IMAGE_FILE_EXECUTABLE_IMAGE,
+            major_linker_version: 0,
+            minor_linker_version: 0,
+            address_of_entry_point: 0,
+            image_base: 0x1_4000_0000,
+            major_operating_system_version: 6,
+            minor_operating_system_version: 0,
+            major_image_version: 0,
+            minor_image_version: 0,
+            major_subsystem_version: 6,
+            minor_subsystem_version: 0,
+            subsystem: object::pe::IMAGE_SUBSYSTEM_WINDOWS_GUI,
+            dll_characteristics: 0,
+            size_of_stack_reserve: 0x10_0000,
+            size_of_stack_commit: 0x1000,
+            size_of_heap_reserve: 0x10_0000,
+            size_of_heap_commit: 0x1000,
+        });
+        writer.write_section_headers();
+        writer.write_section(rdata_range.file_offset, rdata);
+        data
+    }
+
+    /// Fixture file that's deleted when the test ends.
+    struct Fixture(PathBuf);
+
+    impl Fixture {
+        fn new(name: &str, contents: &[u8]) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("pinput-exe-test-{}-{name}", std::process::id()));
+            std::fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Read-only data with PICO-8's strings in it. Built at run time,
+    /// since the test executable mustn't look like PICO-8 either.
+    fn pico8_rodata() -> Vec<u8> {
+        let mut rodata = vec![0];
+        for string in pico8_strings() {
+            rodata.extend(string);
+            rodata.push(0);
+        }
+        rodata
+    }
+
+    fn check(name: &str, contents: &[u8]) -> bool {
+        let fixture = Fixture::new(name, contents);
+        ExeInspector::default().is_pico8(&fixture.0).unwrap()
+    }
+
+    #[test]
+    fn recognizes_pico8_elf_strings() {
+        assert!(check("elf-strings", &elf(None, &pico8_rodata())));
+    }
+
+    #[test]
+    fn recognizes_pico8_elf_symbols() {
+        assert!(check("elf-symbols", &elf(Some("_p8_poke"), b"")));
+    }
+
+    #[test]
+    fn recognizes_pico8_pe_strings() {
+        assert!(check("pe-strings", &pe(&pico8_rodata())));
+    }
+
+    #[test]
+    fn rejects_other_executables() {
+        let [name, domain] = pico8_strings();
+        assert!(!check("elf-other", &elf(Some("main"), &name)));
+        assert!(!check("pe-other", &pe(&domain)));
+        let mut script = b"#!/bin/sh\necho ".to_vec();
+        script.extend(pico8_rodata());
+        assert!(!check("not-exe", &script));
+        assert!(!check("empty", b""));
+    }
+
+    #[test]
+    fn pinput_is_not_pico8() {
+        let exe = std::env::current_exe().unwrap();
+        assert!(!is_pico8_exe(&exe, None).unwrap());
+    }
+
+    #[test]
+    fn results_are_cached_per_file() {
+        let fixture = Fixture::new("cached", &elf(Some("_p8_peek"), b""));
+        let mut inspector = ExeInspector::default();
+        assert!(inspector.is_pico8(&fixture.0).unwrap());
+
+        // Same size, same modification time, so it must be the same file.
+        let modified = std::fs::metadata(&fixture.0).unwrap().modified().unwrap();
+        let mut contents = std::fs::read(&fixture.0).unwrap();
+        contents.fill(0);
+        std::fs::write(&fixture.0, &contents).unwrap();
+        File::options()
+            .write(true)
+            .open(&fixture.0)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert!(inspector.is_pico8(&fixture.0).unwrap());
+
+        assert!(!ExeInspector::default().is_pico8(&fixture.0).unwrap());
+    }
+
+    #[test]
+    fn failures_are_cached_per_process() {
+        let fixture = Fixture::new("unreadable", &elf(Some("_p8_peek"), b""));
+        let missing = fixture.0.with_extension("missing");
+        let mut inspector = ExeInspector::default();
+        let process = (1234, 5678);
+        assert!(inspector.is_process_pico8(process, &missing).is_err());
+
+        // Even once there's something to read, until the process has exited.
+        std::fs::rename(&fixture.0, &missing).unwrap();
+        let fixture = Fixture(missing);
+        assert!(inspector.is_process_pico8(process, &fixture.0).is_err());
+        assert!(inspector
+            .is_process_pico8((1234, 9999), &fixture.0)
+            .unwrap());
+
+        inspector.forget_exited(&HashSet::from([(1234, 9999)]));
+        assert!(inspector.is_process_pico8(process, &fixture.0).unwrap());
+
+        // A process that runs another executable gets looked at again.
+        let other = Fixture::new("other", b"#!/bin/sh\n");
+        assert!(!inspector.is_process_pico8(process, &other.0).unwrap());
+    }
+
+    #[test]
+    fn exited_processes_are_forgotten() {
+        let fixture = Fixture::new("forgotten", &elf(Some("_p8_peek"), b""));
+        let mut inspector = ExeInspector::default();
+        assert!(inspector.is_process_pico8((1, 1), &fixture.0).unwrap());
+        assert!(inspector.is_process_pico8((2, 2), &fixture.0).unwrap());
+        assert_eq!(inspector.cache.len(), 1);
+
+        inspector.forget_exited(&HashSet::from([(2, 2)]));
+        assert_eq!(inspector.processes.len(), 1);
+        assert_eq!(inspector.cache.len(), 1);
+
+        inspector.forget_exited(&HashSet::new());
+        assert!(inspector.processes.is_empty());
+        assert!(inspector.cache.is_empty());
+    }
+}
@@ -1,79 +1,18 @@
-use bitflags::bitflags;
+use pinput_protocol::{PinputGamepad, PinputGamepadButtons, PinputGamepadFlags};
 use sdl2::controller::{Axis, Button, GameController};
 use sdl2::joystick::{Joystick, PowerLevel};
+use std::ffi::CStr;
 
-use crate::constants::{FRAME_DURATION_MS, PINPUT_MAX_GAMEPADS};
+use crate::constants::{CHORD_WINDOW_MS, FRAME_DURATION_MS, GUIDE_TAP_MS};
 use crate::error::Error;
+use crate::gamepad_source::{clear_gamepad, GamepadSource};
+use crate::slots::ControllerId;
 
-bitflags! {
-    /// Gamepad informational flags.
-    #[derive(Default)]
-    pub struct PinputGamepadFlags: u8 {
-        /// This gamepad is connected.
-        const CONNECTED = 1 << 0;
-
-        /// This gamepad has a battery.
-        /// If this is true, the `battery` field and the `Charging` flag may be non-zero.
-        const HAS_BATTERY = 1 << 1;
-
-        /// This gamepad is currently charging its battery.
-        const CHARGING = 1 << 2;
-
-        /// Does this controller have a usable guide button?
-        /// Not all Apple-supported gamepads have a guide button,
-        /// and versions of Pinput on other platforms might not have access to them
-        /// (XInput on Windows, for example).
-        const HAS_GUIDE_BUTTON = 1 << 3;
-
-        /// Does this controller have a misc or touchpad-click button?
-        const HAS_MISC_BUTTON = 1 << 4;
-
-        /// Does this controller support vibration?
-        const HAS_RUMBLE = 1 << 5;
-
-        /// Is this gamepad actually a haptic device?
-        const HAPTIC_DEVICE = 1 << 6;
-    }
-}
-
-impl From<PowerLevel> for PinputGamepadFlags {
-    fn from(power_level: PowerLevel) -> Self {
-        match power_level {
-            PowerLevel::Wired | PowerLevel::Unknown => Self::empty(),
-            _ => Self::HAS_BATTERY,
-        }
-    }
-}
-
-bitflags! {
-    /// Flags indicating which buttons are currently pressed.
-    /// Same as <https://docs.microsoft.com/en-us/windows/win32/api/xinput/ns-xinput-xinput_gamepad>
-    /// with the addition of guide and misc buttons in the two unused bits.
-    #[derive(Default)]
-    pub struct PinputGamepadButtons: u16 {
-        const DPAD_UP = 1 << 0;
-        const DPAD_DOWN = 1 << 1;
-        const DPAD_LEFT = 1 << 2;
-        const DPAD_RIGHT = 1 << 3;
-
-        const START = 1 << 4;
-        const BACK = 1 << 5;
-
-        const LEFT_STICK = 1 << 6;
-        const RIGHT_STICK = 1 << 7;
-
-        const LEFT_BUMPER = 1 << 8;
-        const RIGHT_BUMPER = 1 << 9;
-
-        const GUIDE = 1 << 10;
-        /// We map both SDL `Misc1` and `Touchpad` to this.
-        /// No current controllers have both buttons, and they're in approximately the same spot.
-        const MISC = 1 << 11;
-
-        const A = 1 << 12;
-        const B = 1 << 13;
-        const X = 1 << 14;
-        const Y = 1 << 15;
+/// Battery flags implied by an SDL power level.
+fn power_level_flags(power_level: PowerLevel) -> PinputGamepadFlags {
+    match power_level {
+        PowerLevel::Wired | PowerLevel::Unknown => PinputGamepadFlags::empty(),
+        _ => PinputGamepadFlags::HAS_BATTERY,
     }
 }
 
@@ -83,65 +22,30 @@ pub enum PinputGamepadButtonsError {
     Unsupported(Button),
 }
 
-impl TryFrom<Button> for PinputGamepadButtons {
-    type Error = PinputGamepadButtonsError;
-
-    fn try_from(button: Button) -> Result<Self, Self::Error> {
-        match button {
-            Button::A => Ok(Self::A),
-            Button::B => Ok(Self::B),
-            Button::X => Ok(Self::X),
-            Button::Y => Ok(Self::Y),
-            Button::Back => Ok(Self::BACK),
-            Button::Guide => Ok(Self::GUIDE),
-            Button::Start => Ok(Self::START),
-            Button::Misc1 => Ok(Self::MISC),
-            Button::LeftStick => Ok(Self::LEFT_STICK),
-            Button::RightStick => Ok(Self::RIGHT_STICK),
-            Button::LeftShoulder => Ok(Self::LEFT_BUMPER),
-            Button::RightShoulder => Ok(Self::RIGHT_BUMPER),
-            Button::DPadUp => Ok(Self::DPAD_UP),
-            Button::DPadDown => Ok(Self::DPAD_DOWN),
-            Button::DPadLeft => Ok(Self::DPAD_LEFT),
-            Button::DPadRight => Ok(Self::DPAD_RIGHT),
-            Button::Touchpad => Ok(Self::MISC),
-            button => Err(Self::Error::Unsupported(button)),
-        }
+/// Map an SDL button to the corresponding Pinput button flag.
+fn pinput_button(button: Button) -> Result<PinputGamepadButtons, PinputGamepadButtonsError> {
+    match button {
+        Button::A => Ok(PinputGamepadButtons::A),
+        Button::B => Ok(PinputGamepadButtons::B),
+        Button::X => Ok(PinputGamepadButtons::X),
+        Button::Y => Ok(PinputGamepadButtons::Y),
+        Button::Back => Ok(PinputGamepadButtons::BACK),
+        Button::Guide => Ok(PinputGamepadButtons::GUIDE),
+        Button::Start => Ok(PinputGamepadButtons::START),
+        Button::Misc1 => Ok(PinputGamepadButtons::MISC),
+        Button::LeftStick => Ok(PinputGamepadButtons::LEFT_STICK),
+        Button::RightStick => Ok(PinputGamepadButtons::RIGHT_STICK),
+        Button::LeftShoulder => Ok(PinputGamepadButtons::LEFT_BUMPER),
+        Button::RightShoulder => Ok(PinputGamepadButtons::RIGHT_BUMPER),
+        Button::DPadUp => Ok(PinputGamepadButtons::DPAD_UP),
+        Button::DPadDown => Ok(PinputGamepadButtons::DPAD_DOWN),
+        Button::DPadLeft => Ok(PinputGamepadButtons::DPAD_LEFT),
+        Button::DPadRight => Ok(PinputGamepadButtons::DPAD_RIGHT),
+        Button::Touchpad => Ok(PinputGamepadButtons::MISC),
+        button => Err(PinputGamepadButtonsError::Unsupported(button)),
     }
 }
 
-/// Structure representing a gamepad to the runtime.
-/// Based on <https://docs.microsoft.com/en-us/windows/win32/api/xinput/ns-xinput-xinput_gamepad>
-/// and <https://docs.microsoft.com/en-us/windows/win32/api/xinput/ns-xinput-xinput_vibration>
-/// but prefixed with controller flags and a battery meter, and with smaller rumble types to fit
-/// into a convenient size (16 bytes). All fields are written to the runtime,
-/// except for the rumble fields, which are read from the runtime.
-#[repr(C, packed)]
-#[derive(Copy, Clone, Debug, Default)]
-pub struct PinputGamepad {
-    pub flags: PinputGamepadFlags,
-    /// 0 for empty or not present, max value for fully charged.
-    pub battery: u8,
-    pub buttons: PinputGamepadButtons,
-
-    pub left_trigger: u8,
-    pub right_trigger: u8,
-
-    pub left_stick_x: i16,
-    pub left_stick_y: i16,
-
-    pub right_stick_x: i16,
-    pub right_stick_y: i16,
-
-    /// Output from the runtime.
-    pub lo_freq_rumble: u8,
-    /// Output from the runtime.
-    pub hi_freq_rumble: u8,
-}
-
-/// Fill GPIO with gamepads.
-pub type PinputGamepadArray = [PinputGamepad; PINPUT_MAX_GAMEPADS];
-
 /// There's no convenient way to iterate over an enum,
 /// but fortunately this one doesn't change very often.
 const SDL_GAME_CONTROLLER_BUTTONS: [Button; 17] = [
@@ -164,6 +68,65 @@ const SDL_GAME_CONTROLLER_BUTTONS: [Button; 17] = [
     Button::Touchpad,
 ];
 
+/// Where a controller's Guide button is in becoming a slot-changing chord.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GuideChord {
+    /// Guide isn't held.
+    #[default]
+    Released,
+    /// Guide has been held for this many frames without a D-pad press.
+    /// The cartridge doesn't see it yet, in case it turns into a chord.
+    Pending(u64),
+    /// Guide has been held too long to start a chord, so the cartridge sees it.
+    PassedThrough,
+    /// Guide and a D-pad direction made a chord, so the cartridge doesn't see either
+    /// until Guide is released.
+    Held,
+    /// Guide was tapped while pending, so the cartridge sees it for this many more frames.
+    Tapped(u64),
+}
+
+impl GuideChord {
+    /// Call when Guide and a D-pad direction have changed slots.
+    pub fn start(&mut self) {
+        *self = GuideChord::Held;
+    }
+
+    /// Hide or replay Guide in this frame's buttons, and move on to the next frame.
+    pub fn filter(&mut self, buttons: PinputGamepadButtons) -> PinputGamepadButtons {
+        let window_frames = CHORD_WINDOW_MS / FRAME_DURATION_MS;
+        let tap_frames = GUIDE_TAP_MS.div_ceil(FRAME_DURATION_MS);
+        let guide = PinputGamepadButtons::GUIDE;
+        let (next, shown) = match (*self, buttons.contains(guide)) {
+            (GuideChord::Held, true) => (GuideChord::Held, buttons.difference(CHORD_BUTTONS)),
+            (GuideChord::PassedThrough, true) => (GuideChord::PassedThrough, buttons),
+            (GuideChord::Pending(frames), true) if frames >= window_frames => {
+                (GuideChord::PassedThrough, buttons)
+            }
+            (GuideChord::Pending(frames), true) => {
+                (GuideChord::Pending(frames + 1), buttons.difference(guide))
+            }
+            (_, true) => (GuideChord::Pending(1), buttons.difference(guide)),
+            (GuideChord::Pending(_), false) => {
+                (GuideChord::Tapped(tap_frames - 1), buttons.union(guide))
+            }
+            (GuideChord::Tapped(frames), false) if frames > 0 => {
+                (GuideChord::Tapped(frames - 1), buttons.union(guide))
+            }
+            (_, false) => (GuideChord::Released, buttons),
+        };
+        *self = next;
+        shown
+    }
+}
+
+/// Buttons that Pinput keeps for itself while a slot-changing chord is held.
+const CHORD_BUTTONS: PinputGamepadButtons = PinputGamepadButtons::GUIDE
+    .union(PinputGamepadButtons::DPAD_UP)
+    .union(PinputGamepadButtons::DPAD_DOWN)
+    .union(PinputGamepadButtons::DPAD_LEFT)
+    .union(PinputGamepadButtons::DPAD_RIGHT);
+
 /// SDL has two subsystems for accessing parts of the same device.
 pub struct SdlGamepad {
     /// Used to check the power level.
@@ -172,82 +135,175 @@ pub struct SdlGamepad {
     pub game_controller: GameController,
     /// Can this gamepad rumble? We can only test by trying it.
     pub has_rumble: bool,
+    /// Whether Guide is, or might become, part of a slot-changing chord,
+    /// which decides what the cartridge sees of Guide and the D-pad.
+    pub guide_chord: GuideChord,
 }
 
-pub fn sync_gamepad(
-    sdl_gamepad: &mut SdlGamepad,
-    gamepad: &mut PinputGamepad,
-) -> Result<(), Error> {
-    let game_controller = &mut sdl_gamepad.game_controller;
-    let joystick = &sdl_gamepad.joystick;
-
-    if !game_controller.attached() {
-        *gamepad = PinputGamepad::default();
-        return Ok(());
+impl SdlGamepad {
+    /// Identity used to give this controller the same slot when it reconnects.
+    pub fn controller_id(&self) -> ControllerId {
+        ControllerId {
+            guid: self.joystick.guid().string(),
+            serial: self.serial(),
+        }
     }
 
-    // Set rumble effects, if we can.
-    if sdl_gamepad.has_rumble {
-        game_controller.set_rumble(
-            ((gamepad.lo_freq_rumble as f64) / (u8::MAX as f64) * (u16::MAX as f64)) as u16,
-            ((gamepad.hi_freq_rumble as f64) / (u8::MAX as f64) * (u16::MAX as f64)) as u16,
-            // Setting one frame of rumble leads to choppiness as the effect may expire early.
-            2 * FRAME_DURATION_MS as u32,
-        )?;
+    /// The `sdl2` crate doesn't wrap `SDL_GameControllerGetSerial` yet.
+    fn serial(&self) -> Option<String> {
+        unsafe {
+            // Doesn't take a new reference, so there's nothing to close.
+            let raw = sdl2::sys::SDL_GameControllerFromInstanceID(
+                self.game_controller.instance_id() as sdl2::sys::SDL_JoystickID,
+            );
+            if raw.is_null() {
+                return None;
+            }
+            let serial = sdl2::sys::SDL_GameControllerGetSerial(raw);
+            if serial.is_null() {
+                return None;
+            }
+            Some(CStr::from_ptr(serial).to_string_lossy().into_owned())
+                .filter(|serial| !serial.is_empty())
+        }
     }
+}
 
-    // Read gamepad capabilities and power level.
-    gamepad.flags = PinputGamepadFlags::default();
-    gamepad.flags.insert(PinputGamepadFlags::CONNECTED);
-    let mapping = game_controller.mapping();
-    if mapping.contains("guide:") {
-        gamepad.flags.insert(PinputGamepadFlags::HAS_GUIDE_BUTTON);
+impl GamepadSource for SdlGamepad {
+    fn name(&self) -> String {
+        self.game_controller.name()
     }
-    if mapping.contains("misc1:") || mapping.contains("touchpad:") {
-        gamepad.flags.insert(PinputGamepadFlags::HAS_MISC_BUTTON);
-    }
-    if sdl_gamepad.has_rumble {
-        gamepad.flags.insert(PinputGamepadFlags::HAS_RUMBLE);
+
+    fn rumble(&mut self, lo_freq_rumble: u8, hi_freq_rumble: u8) -> Result<(), Error> {
+        // Set rumble effects, if we can.
+        if self.has_rumble && self.game_controller.attached() {
+            self.game_controller.set_rumble(
+                ((lo_freq_rumble as f64) / (u8::MAX as f64) * (u16::MAX as f64)) as u16,
+                ((hi_freq_rumble as f64) / (u8::MAX as f64) * (u16::MAX as f64)) as u16,
+                // Setting one frame of rumble leads to choppiness as the effect may expire early.
+                2 * FRAME_DURATION_MS as u32,
+            )?;
+        }
+        Ok(())
     }
-    // SDL doesn't currently have a way to tell if a gamepad is charging.
-    let power_level = joystick.power_level()?;
-    gamepad.flags.insert(PinputGamepadFlags::from(power_level));
-    match power_level {
-        PowerLevel::Low => {
-            gamepad.battery = u8::MAX / 3;
+
+    fn fill(&mut self, gamepad: &mut PinputGamepad) -> Result<(), Error> {
+        let game_controller = &self.game_controller;
+        let joystick = &self.joystick;
+
+        if !game_controller.attached() {
+            clear_gamepad(gamepad);
+            return Ok(());
         }
-        PowerLevel::Medium => {
-            gamepad.battery = u8::MAX / 3 * 2;
+
+        // Read gamepad capabilities and power level.
+        gamepad.flags = PinputGamepadFlags::default();
+        gamepad.flags.insert(PinputGamepadFlags::CONNECTED);
+        let mapping = game_controller.mapping();
+        if mapping.contains("guide:") {
+            gamepad.flags.insert(PinputGamepadFlags::HAS_GUIDE_BUTTON);
         }
-        PowerLevel::Full => {
-            gamepad.battery = u8::MAX;
+        if mapping.contains("misc1:") || mapping.contains("touchpad:") {
+            gamepad.flags.insert(PinputGamepadFlags::HAS_MISC_BUTTON);
         }
-        _ => {
-            gamepad.battery = 0;
+        if self.has_rumble {
+            gamepad.flags.insert(PinputGamepadFlags::HAS_RUMBLE);
+        }
+        // SDL doesn't currently have a way to tell if a gamepad is charging.
+        let power_level = joystick.power_level()?;
+        gamepad.flags.insert(power_level_flags(power_level));
+        match power_level {
+            PowerLevel::Low => {
+                gamepad.battery = u8::MAX / 3;
+            }
+            PowerLevel::Medium => {
+                gamepad.battery = u8::MAX / 3 * 2;
+            }
+            PowerLevel::Full => {
+                gamepad.battery = u8::MAX;
+            }
+            _ => {
+                gamepad.battery = 0;
+            }
         }
-    }
 
-    // Read gamepad buttons.
-    // Temporary variable used to avoid an unaligned access.
-    let mut buttons = PinputGamepadButtons::default();
-    for button in SDL_GAME_CONTROLLER_BUTTONS {
-        if game_controller.button(button) {
-            if let Ok(button) = PinputGamepadButtons::try_from(button) {
-                buttons.insert(button);
+        // Read gamepad buttons.
+        // Temporary variable used to avoid an unaligned access.
+        let mut buttons = PinputGamepadButtons::default();
+        for button in SDL_GAME_CONTROLLER_BUTTONS {
+            if game_controller.button(button) {
+                if let Ok(button) = pinput_button(button) {
+                    buttons.insert(button);
+                }
             }
         }
+        gamepad.buttons = self.guide_chord.filter(buttons);
+
+        // Read gamepad axes (including triggers).
+        // Note that SDL Y axes are upside-down compared to XInput:
+        // <https://github.com/libsdl-org/SDL/blob/9130f7c/src/joystick/windows/SDL_xinputjoystick.c#L462-L465>
+        gamepad.left_stick_x = game_controller.axis(Axis::LeftX);
+        gamepad.left_stick_y = !game_controller.axis(Axis::LeftY);
+        gamepad.right_stick_x = game_controller.axis(Axis::RightX);
+        gamepad.right_stick_y = !game_controller.axis(Axis::RightY);
+        gamepad.left_trigger = (game_controller.axis(Axis::TriggerLeft) / 0x81) as u8;
+        gamepad.right_trigger = (game_controller.axis(Axis::TriggerRight) / 0x81) as u8;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUIDE: PinputGamepadButtons = PinputGamepadButtons::GUIDE;
+    const NONE: PinputGamepadButtons = PinputGamepadButtons::empty();
+
+    /// Run `frames` frames of the same buttons, and collect what the cartridge sees.
+    fn run(
+        chord: &mut GuideChord,
+        buttons: PinputGamepadButtons,
+        frames: u64,
+    ) -> Vec<PinputGamepadButtons> {
+        (0..frames).map(|_| chord.filter(buttons)).collect()
+    }
+
+    #[test]
+    fn guide_tap_is_held_back_then_replayed() {
+        let mut chord = GuideChord::default();
+        assert_eq!(run(&mut chord, GUIDE, 3), [NONE; 3]);
+        let tap_frames = GUIDE_TAP_MS.div_ceil(FRAME_DURATION_MS) as usize;
+        let after = run(&mut chord, NONE, tap_frames as u64 + 1);
+        assert_eq!(after[..tap_frames], vec![GUIDE; tap_frames]);
+        assert_eq!(after[tap_frames], NONE);
+        assert_eq!(chord, GuideChord::Released);
+    }
+
+    #[test]
+    fn guide_chord_never_reaches_the_cartridge() {
+        let mut chord = GuideChord::default();
+        assert_eq!(run(&mut chord, GUIDE, 2), [NONE; 2]);
+        chord.start();
+        let pressed = GUIDE | PinputGamepadButtons::DPAD_RIGHT | PinputGamepadButtons::A;
+        assert_eq!(
+            run(&mut chord, pressed, 100),
+            [PinputGamepadButtons::A; 100]
+        );
+        // Releasing Guide after a chord doesn't replay it.
+        assert_eq!(run(&mut chord, NONE, 1), [NONE]);
+        assert_eq!(chord, GuideChord::Released);
+    }
+
+    #[test]
+    fn long_guide_hold_passes_through() {
+        let mut chord = GuideChord::default();
+        let window_frames = CHORD_WINDOW_MS / FRAME_DURATION_MS;
+        let seen = run(&mut chord, GUIDE, window_frames + 5);
+        assert!(seen[..window_frames as usize]
+            .iter()
+            .all(|buttons| buttons.is_empty()));
+        assert_eq!(seen[window_frames as usize..], [GUIDE; 5]);
+        assert_eq!(run(&mut chord, NONE, 1), [NONE]);
     }
-    gamepad.buttons = buttons;
-
-    // Read gamepad axes (including triggers).
-    // Note that SDL Y axes are upside-down compared to XInput:
-    // <https://github.com/libsdl-org/SDL/blob/9130f7c/src/joystick/windows/SDL_xinputjoystick.c#L462-L465>
-    gamepad.left_stick_x = game_controller.axis(Axis::LeftX);
-    gamepad.left_stick_y = !game_controller.axis(Axis::LeftY);
-    gamepad.right_stick_x = game_controller.axis(Axis::RightX);
-    gamepad.right_stick_y = !game_controller.axis(Axis::RightY);
-    gamepad.left_trigger = (game_controller.axis(Axis::TriggerLeft) / 0x81) as u8;
-    gamepad.right_trigger = (game_controller.axis(Axis::TriggerRight) / 0x81) as u8;
-
-    Ok(())
 }
@@ -0,0 +1,257 @@
+//! Anything that can occupy a Pinput gamepad slot: SDL controllers, Buttplug devices, or mocks.
+
+use pinput_protocol::{PinputGamepad, PinputGamepadArray, PINPUT_MAX_GAMEPADS};
+use std::cell::Cell;
+
+use crate::error::Error;
+
+/// A producer of gamepad input and consumer of rumble output.
+pub trait GamepadSource {
+    /// Human-readable name for logging.
+    fn name(&self) -> String;
+
+    /// Apply rumble values most recently written by the runtime.
+    fn rumble(&mut self, lo_freq_rumble: u8, hi_freq_rumble: u8) -> Result<(), Error>;
+
+    /// Fill in everything but the rumble fields, which belong to the runtime.
+    fn fill(&mut self, gamepad: &mut PinputGamepad) -> Result<(), Error>;
+}
+
+/// Feed each gamepad slot from the source in the same position:
+/// pass the runtime's rumble values to the source, then let it fill in its input.
+/// Slots without a source are marked as disconnected, keeping their rumble values.
+pub fn sync_sources<'a>(
+    sources: impl IntoIterator<Item = Option<&'a mut dyn GamepadSource>>,
+    gamepads: &mut PinputGamepadArray,
+) -> Result<(), Error> {
+    let mut sources = sources.into_iter();
+    for gamepad in gamepads.iter_mut() {
+        match sources.next().flatten() {
+            Some(source) => {
+                source.rumble(gamepad.lo_freq_rumble, gamepad.hi_freq_rumble)?;
+                source.fill(gamepad)?;
+            }
+            None => clear_gamepad(gamepad),
+        }
+    }
+    Ok(())
+}
+
+/// Reset a slot to the disconnected state without touching runtime-owned rumble values.
+pub fn clear_gamepad(gamepad: &mut PinputGamepad) {
+    *gamepad = PinputGamepad {
+        lo_freq_rumble: gamepad.lo_freq_rumble,
+        hi_freq_rumble: gamepad.hi_freq_rumble,
+        ..Default::default()
+    };
+}
+
+/// Rumble for each slot, combined over every runtime that sees that slot this frame.
+/// Runtimes sharing a controller would otherwise take turns setting its rumble,
+/// so each motor gets the strongest value any of them asks for.
+#[derive(Debug, Default)]
+pub struct RumbleMix {
+    slots: [Cell<Option<(u8, u8)>>; PINPUT_MAX_GAMEPADS],
+}
+
+impl RumbleMix {
+    /// Stand-ins for the sources of every slot, for syncing one runtime:
+    /// they fill slots from the real sources, but their rumble goes into this mix.
+    /// Pass them through [`RuntimePolicy::select`](crate::runtime_policy::RuntimePolicy::select)
+    /// like the real ones.
+    pub fn sources<'a>(
+        &'a self,
+        sources: impl IntoIterator<Item = Option<&'a mut dyn GamepadSource>>,
+    ) -> Vec<Option<MixedSource<'a>>> {
+        sources
+            .into_iter()
+            .zip(&self.slots)
+            .map(|(source, rumble)| source.map(|source| MixedSource { source, rumble }))
+            .collect()
+    }
+
+    /// Pass the combined rumble to the real sources of every slot that a runtime saw.
+    /// Keeps going on error, so that one failing device doesn't stop the others rumbling.
+    pub fn apply<'a>(
+        self,
+        sources: impl IntoIterator<Item = Option<&'a mut dyn GamepadSource>>,
+    ) -> Result<(), Error> {
+        let mut result = Ok(());
+        for (source, rumble) in sources.into_iter().zip(self.slots) {
+            if let (Some(source), Some((lo_freq_rumble, hi_freq_rumble))) = (source, rumble.get()) {
+                if let Err(err) = source.rumble(lo_freq_rumble, hi_freq_rumble) {
+                    result = Err(err);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// A slot's source as seen by one runtime. See [`RumbleMix::sources`].
+pub struct MixedSource<'a> {
+    source: &'a mut dyn GamepadSource,
+    rumble: &'a Cell<Option<(u8, u8)>>,
+}
+
+impl GamepadSource for MixedSource<'_> {
+    fn name(&self) -> String {
+        self.source.name()
+    }
+
+    fn rumble(&mut self, lo_freq_rumble: u8, hi_freq_rumble: u8) -> Result<(), Error> {
+        let (lo, hi) = self.rumble.get().unwrap_or_default();
+        self.rumble
+            .set(Some((lo.max(lo_freq_rumble), hi.max(hi_freq_rumble))));
+        Ok(())
+    }
+
+    fn fill(&mut self, gamepad: &mut PinputGamepad) -> Result<(), Error> {
+        self.source.fill(gamepad)
+    }
+}
+
+/// In-memory gamepad for tests and harnesses:
+/// reports a fixed state and records the last rumble values it was given.
+#[derive(Debug, Clone, Default)]
+pub struct MockGamepad {
+    pub name: String,
+    /// Copied into the slot on every sync, except for the rumble fields.
+    pub state: PinputGamepad,
+    /// Last rumble values received, as `(lo_freq_rumble, hi_freq_rumble)`.
+    pub rumble: (u8, u8),
+}
+
+impl MockGamepad {
+    pub fn new(name: impl Into<String>, state: PinputGamepad) -> Self {
+        Self {
+            name: name.into(),
+            state,
+            rumble: (0, 0),
+        }
+    }
+}
+
+impl GamepadSource for MockGamepad {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn rumble(&mut self, lo_freq_rumble: u8, hi_freq_rumble: u8) -> Result<(), Error> {
+        self.rumble = (lo_freq_rumble, hi_freq_rumble);
+        Ok(())
+    }
+
+    fn fill(&mut self, gamepad: &mut PinputGamepad) -> Result<(), Error> {
+        *gamepad = PinputGamepad {
+            lo_freq_rumble: gamepad.lo_freq_rumble,
+            hi_freq_rumble: gamepad.hi_freq_rumble,
+            ..self.state
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pinput_protocol::{PinputGamepadButtons, PinputGamepadFlags};
+
+    fn connected(buttons: PinputGamepadButtons) -> PinputGamepad {
+        PinputGamepad {
+            flags: PinputGamepadFlags::CONNECTED,
+            buttons,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sources_fill_slots_in_order() {
+        let mut a = MockGamepad::new("a", connected(PinputGamepadButtons::A));
+        let mut b = MockGamepad::new("b", connected(PinputGamepadButtons::B));
+        let mut gamepads = PinputGamepadArray::default();
+
+        sync_sources(
+            [
+                Some(&mut a as &mut dyn GamepadSource),
+                None,
+                Some(&mut b as &mut dyn GamepadSource),
+            ],
+            &mut gamepads,
+        )
+        .unwrap();
+
+        assert_eq!({ gamepads[0].buttons }, PinputGamepadButtons::A);
+        assert!(!{ gamepads[1].flags }.contains(PinputGamepadFlags::CONNECTED));
+        assert_eq!({ gamepads[2].buttons }, PinputGamepadButtons::B);
+        for gamepad in &gamepads[3..] {
+            assert!(!{ gamepad.flags }.contains(PinputGamepadFlags::CONNECTED));
+        }
+    }
+
+    #[test]
+    fn rumble_goes_to_source_and_stays_in_slot() {
+        let mut a = MockGamepad::new("a", connected(PinputGamepadButtons::empty()));
+        let mut gamepads = PinputGamepadArray::default();
+        gamepads[0].lo_freq_rumble = 12;
+        gamepads[0].hi_freq_rumble = 34;
+        gamepads[1].lo_freq_rumble = 56;
+
+        sync_sources([Some(&mut a as &mut dyn GamepadSource)], &mut gamepads).unwrap();
+
+        assert_eq!(a.rumble, (12, 34));
+        assert_eq!(gamepads[0].lo_freq_rumble, 12);
+        assert_eq!(gamepads[0].hi_freq_rumble, 34);
+        assert_eq!(gamepads[1].lo_freq_rumble, 56);
+    }
+
+    #[test]
+    fn mirrored_rumble_takes_the_strongest() {
+        let mut a = MockGamepad::new("a", connected(PinputGamepadButtons::A));
+        let mut b = MockGamepad::new("b", connected(PinputGamepadButtons::B));
+        let mix = RumbleMix::default();
+        let runtimes = [[(200, 0), (0, 0)], [(10, 90), (0, 0)]];
+        for rumble in runtimes {
+            let mut gamepads = PinputGamepadArray::default();
+            for (gamepad, (lo, hi)) in gamepads.iter_mut().zip(rumble) {
+                gamepad.lo_freq_rumble = lo;
+                gamepad.hi_freq_rumble = hi;
+            }
+            let mut sources = mix.sources([
+                Some(&mut a as &mut dyn GamepadSource),
+                Some(&mut b as &mut dyn GamepadSource),
+            ]);
+            sync_sources(
+                sources.iter_mut().map(|source| {
+                    source
+                        .as_mut()
+                        .map(|source| source as &mut dyn GamepadSource)
+                }),
+                &mut gamepads,
+            )
+            .unwrap();
+            // Sources are still filled in, but don't rumble until everyone has had a say.
+            assert_eq!({ gamepads[1].buttons }, PinputGamepadButtons::B);
+        }
+        assert_eq!(a.rumble, (0, 0));
+
+        mix.apply([
+            Some(&mut a as &mut dyn GamepadSource),
+            Some(&mut b as &mut dyn GamepadSource),
+        ])
+        .unwrap();
+        assert_eq!(a.rumble, (200, 90));
+        assert_eq!(b.rumble, (0, 0));
+    }
+
+    #[test]
+    fn empty_slots_are_cleared() {
+        let mut gamepads = PinputGamepadArray::default();
+        gamepads[3] = connected(PinputGamepadButtons::START);
+
+        sync_sources(std::iter::empty(), &mut gamepads).unwrap();
+
+        assert!(!{ gamepads[3].flags }.contains(PinputGamepadFlags::CONNECTED));
+        assert!({ gamepads[3].buttons }.is_empty());
+    }
+}
@@ -0,0 +1,815 @@
+//! Access to a runtime's 128-byte GPIO block, wherever it lives.
+
+use pinput_protocol::{
+    decode_gamepads, encode_gamepads, has_magic, offsets, PinputGamepadArray, PINPUT_GAMEPAD_SIZE,
+    PINPUT_GPIO_SIZE, PINPUT_MAGIC, PINPUT_MAX_GAMEPADS,
+};
+use process_memory::{DataMember, Memory, ProcessHandle, PutAddress};
+use std::fmt::Debug;
+#[cfg(target_os = "linux")]
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::Range;
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::FileExt;
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::gamepad_source::{sync_sources, GamepadSource};
+
+/// Reads and writes the GPIO block shared with a runtime.
+/// Errors from any of these methods usually mean that the runtime has gone away.
+pub trait GpioBackend: Debug {
+    /// Has the cartridge written Pinput magic that we haven't cleared yet?
+    /// Always reads memory afresh.
+    fn has_magic(&mut self) -> io::Result<bool>;
+
+    /// Read the whole GPIO block.
+    fn read(&mut self) -> io::Result<[u8; PINPUT_GPIO_SIZE]>;
+
+    /// Write the bytes of each record that Pinput owns. See [`host_spans`].
+    /// The cartridge's rumble bytes are left alone, so that rumble it writes
+    /// between our read and this write isn't lost.
+    fn write(&mut self, gpio: &[u8; PINPUT_GPIO_SIZE]) -> io::Result<()> {
+        self.write_spans(gpio, &host_spans().collect::<Vec<_>>())
+    }
+
+    /// Write only these ranges of `gpio`, each of which lies within one of the [`handshake_spans`].
+    fn write_spans(
+        &mut self,
+        gpio: &[u8; PINPUT_GPIO_SIZE],
+        spans: &[Range<usize>],
+    ) -> io::Result<()>;
+
+    /// Follow the GPIO block to a new address in the same process, after the runtime moved it.
+    fn relocate(&mut self, _gpio_address: usize) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Where in the GPIO block Pinput writes: each record from its flags through its right stick.
+/// The rest of each record is rumble, which belongs to the cartridge.
+pub fn host_spans() -> impl Iterator<Item = Range<usize>> {
+    (0..PINPUT_MAX_GAMEPADS).map(|slot| {
+        let start = slot * PINPUT_GAMEPAD_SIZE;
+        start..start + offsets::LO_FREQ_RUMBLE
+    })
+}
+
+/// What the handshake writes: the host spans, with the first one stretched over the whole magic.
+/// The end of the magic lands in the first slot's rumble bytes, so those are cleared once here,
+/// while we know they hold magic rather than anything the cartridge wants.
+pub fn handshake_spans() -> impl Iterator<Item = Range<usize>> {
+    host_spans().map(|span| {
+        if span.start == 0 {
+            0..PINPUT_MAGIC.len()
+        } else {
+            span
+        }
+    })
+}
+
+/// The host spans of slots whose bytes differ from what we last wrote, or all of them
+/// if we haven't written anything yet.
+pub fn changed_spans(
+    last_written: Option<&[u8; PINPUT_GPIO_SIZE]>,
+    gpio: &[u8; PINPUT_GPIO_SIZE],
+) -> Vec<Range<usize>> {
+    host_spans()
+        .filter(|span| {
+            last_written.is_none_or(|last_written| last_written[span.clone()] != gpio[span.clone()])
+        })
+        .collect()
+}
+
+/// GPIO in another process, accessed through `process_memory`.
+#[derive(Debug)]
+pub struct ProcessMemoryGpio {
+    handle: ProcessHandle,
+    gpio_address: usize,
+    /// First 16 bytes of GPIO, where the cartridge writes Pinput magic.
+    gpio_as_magic: DataMember<[u8; 16]>,
+    /// All 128 bytes of GPIO.
+    gpio_as_bytes: DataMember<[u8; PINPUT_GPIO_SIZE]>,
+}
+
+impl ProcessMemoryGpio {
+    pub fn new(handle: ProcessHandle, gpio_address: usize) -> Self {
+        Self {
+            handle,
+            gpio_address,
+            gpio_as_magic: DataMember::new_offset(handle, vec![gpio_address]),
+            gpio_as_bytes: DataMember::new_offset(handle, vec![gpio_address]),
+        }
+    }
+}
+
+impl GpioBackend for ProcessMemoryGpio {
+    fn has_magic(&mut self) -> io::Result<bool> {
+        // Any bit pattern is a valid byte array, so these reads are sound.
+        Ok(unsafe { self.gpio_as_magic.read() }? == PINPUT_MAGIC)
+    }
+
+    fn read(&mut self) -> io::Result<[u8; PINPUT_GPIO_SIZE]> {
+        unsafe { self.gpio_as_bytes.read() }
+    }
+
+    fn write_spans(
+        &mut self,
+        gpio: &[u8; PINPUT_GPIO_SIZE],
+        spans: &[Range<usize>],
+    ) -> io::Result<()> {
+        for span in spans {
+            self.handle
+                .put_address(self.gpio_address + span.start, &gpio[span.clone()])?;
+        }
+        Ok(())
+    }
+
+    fn relocate(&mut self, gpio_address: usize) -> io::Result<()> {
+        *self = Self::new(self.handle, gpio_address);
+        Ok(())
+    }
+}
+
+/// GPIO in another process, read through its `/proc/<pid>/mem` file.
+/// The file stays open for the life of the connection, so each frame costs one `pread`,
+/// and a recycled PID can't point it at another process's memory.
+/// Writes of scattered slots go out in one `process_vm_writev` call where the kernel has it.
+/// That goes by PID rather than through the file, so each batch first checks a pidfd
+/// to make sure the runtime hasn't exited and left its PID free for reuse.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct ProcMemGpio {
+    mem: File,
+    pid: process_memory::Pid,
+    gpio_address: usize,
+    /// The runtime, for as long as we can batch writes to it.
+    /// `None` if the kernel was built without pidfds or `process_vm_writev`,
+    /// after which we write each span to the memory file instead.
+    pidfd: Option<OwnedFd>,
+}
+
+#[cfg(target_os = "linux")]
+impl ProcMemGpio {
+    pub fn open(pid: process_memory::Pid, gpio_address: usize) -> io::Result<Self> {
+        let pidfd = match pidfd_open(pid) {
+            Ok(pidfd) => Some(pidfd),
+            Err(err) if err.raw_os_error() == Some(libc::ENOSYS) => None,
+            Err(err) => return Err(err),
+        };
+        let mem = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/proc/{pid}/mem"))?;
+        // If the process behind the pidfd is still running, the PID can't have been reused
+        // in between, so the memory file belongs to the same process.
+        if let Some(pidfd) = &pidfd {
+            if has_exited(pidfd)? {
+                return Err(io::Error::from_raw_os_error(libc::ESRCH));
+            }
+        }
+        Ok(Self {
+            mem,
+            pid,
+            gpio_address,
+            pidfd,
+        })
+    }
+
+    /// Write all of `spans` in one system call, if the runtime still has its PID.
+    fn vm_writev(
+        &self,
+        pidfd: &OwnedFd,
+        gpio: &[u8; PINPUT_GPIO_SIZE],
+        spans: &[Range<usize>],
+    ) -> io::Result<()> {
+        if has_exited(pidfd)? {
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+        let local: Vec<libc::iovec> = spans
+            .iter()
+            .map(|span| libc::iovec {
+                iov_base: gpio[span.clone()].as_ptr() as *mut libc::c_void,
+                iov_len: span.len(),
+            })
+            .collect();
+        let remote: Vec<libc::iovec> = spans
+            .iter()
+            .map(|span| libc::iovec {
+                iov_base: (self.gpio_address + span.start) as *mut libc::c_void,
+                iov_len: span.len(),
+            })
+            .collect();
+        // The kernel only reads from the local buffers, which outlive the call,
+        // and checks the remote ones against the other process's mappings.
+        let written = unsafe {
+            libc::process_vm_writev(
+                self.pid,
+                local.as_ptr(),
+                local.len() as libc::c_ulong,
+                remote.as_ptr(),
+                remote.len() as libc::c_ulong,
+                0,
+            )
+        };
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Self::check_transferred(written as usize, spans.iter().map(Range::len).sum())
+    }
+
+    /// The kernel reports a process that has exited as the end of its memory file.
+    /// Call that what it is, so that callers can tell it from an unmapped address.
+    fn check_transferred(transferred: usize, expected: usize) -> io::Result<()> {
+        if transferred == 0 {
+            Err(io::Error::from_raw_os_error(libc::ESRCH))
+        } else if transferred < expected {
+            Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("only transferred {transferred} of {expected} bytes of GPIO"),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl GpioBackend for ProcMemGpio {
+    fn has_magic(&mut self) -> io::Result<bool> {
+        Ok(has_magic(&self.read()?))
+    }
+
+    fn read(&mut self) -> io::Result<[u8; PINPUT_GPIO_SIZE]> {
+        let mut gpio = [0; PINPUT_GPIO_SIZE];
+        Self::check_transferred(
+            self.mem.read_at(&mut gpio, self.gpio_address as u64)?,
+            PINPUT_GPIO_SIZE,
+        )?;
+        Ok(gpio)
+    }
+
+    fn write_spans(
+        &mut self,
+        gpio: &[u8; PINPUT_GPIO_SIZE],
+        spans: &[Range<usize>],
+    ) -> io::Result<()> {
+        if spans.is_empty() {
+            return Ok(());
+        }
+        if let Some(pidfd) = &self.pidfd {
+            match self.vm_writev(pidfd, gpio, spans) {
+                Err(err) if err.raw_os_error() == Some(libc::ENOSYS) => self.pidfd = None,
+                result => return result,
+            }
+        }
+        for span in spans {
+            let offset = (self.gpio_address + span.start) as u64;
+            Self::check_transferred(self.mem.write_at(&gpio[span.clone()], offset)?, span.len())?;
+        }
+        Ok(())
+    }
+
+    fn relocate(&mut self, gpio_address: usize) -> io::Result<()> {
+        self.gpio_address = gpio_address;
+        Ok(())
+    }
+}
+
+/// A handle on process `pid` that stays with it even if the PID is reused.
+#[cfg(target_os = "linux")]
+fn pidfd_open(pid: process_memory::Pid) -> io::Result<OwnedFd> {
+    // No flags, and the kernel hands back a new file descriptor that nothing else owns.
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// A pidfd becomes readable once its process has exited.
+#[cfg(target_os = "linux")]
+fn has_exited(pidfd: &OwnedFd) -> io::Result<bool> {
+    let mut poll_fd = libc::pollfd {
+        fd: pidfd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // One pollfd that outlives the call, and no waiting.
+    if unsafe { libc::poll(&mut poll_fd, 1, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(poll_fd.revents != 0)
+}
+
+/// GPIO in a plain buffer, for tests and for runtimes that live in our own process.
+impl GpioBackend for [u8; PINPUT_GPIO_SIZE] {
+    fn has_magic(&mut self) -> io::Result<bool> {
+        Ok(has_magic(self))
+    }
+
+    fn read(&mut self) -> io::Result<[u8; PINPUT_GPIO_SIZE]> {
+        Ok(*self)
+    }
+
+    fn write_spans(
+        &mut self,
+        gpio: &[u8; PINPUT_GPIO_SIZE],
+        spans: &[Range<usize>],
+    ) -> io::Result<()> {
+        for span in spans {
+            self[span.clone()].copy_from_slice(&gpio[span.clone()]);
+        }
+        Ok(())
+    }
+}
+
+/// What happened during one frame of [`sync_gpio`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpioSync {
+    /// The cartridge had just written magic, because it started or restarted.
+    pub handshake: bool,
+    /// The GPIO block as we wrote it.
+    pub written: [u8; PINPUT_GPIO_SIZE],
+    /// How many bytes actually went out, after skipping slots that hadn't changed.
+    pub bytes_written: usize,
+}
+
+/// Bytes written to GPIO per second, measured over windows of at least a second.
+#[derive(Debug, Clone, Copy)]
+pub struct WriteRate {
+    window_start: Instant,
+    window_bytes: usize,
+    per_second: usize,
+}
+
+impl WriteRate {
+    pub fn new(now: Instant) -> Self {
+        WriteRate {
+            window_start: now,
+            window_bytes: 0,
+            per_second: 0,
+        }
+    }
+
+    /// Count a frame's writes. Call this every frame, even ones that wrote nothing,
+    /// so that the rate drops back to zero when things go idle.
+    pub fn record(&mut self, bytes: usize, now: Instant) {
+        self.window_bytes += bytes;
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= Duration::from_secs(1) {
+            self.per_second = (self.window_bytes as f64 / elapsed.as_secs_f64()).round() as usize;
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+    }
+
+    /// The rate over the last full window.
+    pub fn per_second(&self) -> usize {
+        self.per_second
+    }
+}
+
+/// Run one frame of the Pinput protocol against a GPIO block.
+///
+/// If the cartridge has just written magic, we start from empty gamepads and write every slot,
+/// which clears the magic and tells the cartridge that we're connected. See [`handshake_spans`].
+/// Otherwise, we read the gamepads back so that sources get the cartridge's rumble values.
+/// Either way, sources then fill in their slots and the result is written back,
+/// skipping slots that are the same as `last_written`. Outside the handshake,
+/// rumble bytes are never written.
+pub fn sync_gpio<'a>(
+    gpio: &mut dyn GpioBackend,
+    last_written: Option<&[u8; PINPUT_GPIO_SIZE]>,
+    sources: impl IntoIterator<Item = Option<&'a mut dyn GamepadSource>>,
+) -> Result<GpioSync, Error> {
+    // One read serves for both, so the magic check and the gamepads come from the same moment.
+    let read = gpio.read().map_err(Error::Gpio)?;
+    let handshake = has_magic(&read);
+    let mut gamepads = if handshake {
+        PinputGamepadArray::default()
+    } else {
+        decode_gamepads(&read)
+    };
+
+    sync_sources(sources, &mut gamepads)?;
+
+    let written = encode_gamepads(&gamepads);
+    let spans = if handshake {
+        handshake_spans().collect()
+    } else {
+        changed_spans(last_written, &written)
+    };
+    gpio.write_spans(&written, &spans).map_err(Error::Gpio)?;
+    let bytes_written = spans.iter().map(Range::len).sum();
+    Ok(GpioSync {
+        handshake,
+        written,
+        bytes_written,
+    })
+}
+
+/// Do two GPIO blocks agree on everything Pinput writes?
+/// The cartridge owns the rumble bytes, so those can differ.
+pub fn host_bytes_match(a: &[u8; PINPUT_GPIO_SIZE], b: &[u8; PINPUT_GPIO_SIZE]) -> bool {
+    host_spans().all(|span| a[span.clone()] == b[span])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gamepad_source::MockGamepad;
+    use pinput_protocol::{write_magic, PinputGamepad, PinputGamepadButtons, PinputGamepadFlags};
+
+    fn mock() -> MockGamepad {
+        MockGamepad::new(
+            "mock",
+            PinputGamepad {
+                flags: PinputGamepadFlags::CONNECTED | PinputGamepadFlags::HAS_RUMBLE,
+                buttons: PinputGamepadButtons::A,
+                left_stick_x: 0x1234,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn handshake_clears_magic() {
+        let mut gpio = [0u8; PINPUT_GPIO_SIZE];
+        write_magic(&mut gpio);
+        let mut source = mock();
+
+        let sync = sync_gpio(
+            &mut gpio,
+            None,
+            [Some(&mut source as &mut dyn GamepadSource)],
+        )
+        .unwrap();
+
+        assert!(sync.handshake);
+        assert!(host_bytes_match(&sync.written, &gpio));
+        assert_eq!(
+            sync.bytes_written,
+            PINPUT_MAX_GAMEPADS * offsets::LO_FREQ_RUMBLE + 2
+        );
+        assert!(!has_magic(&gpio));
+        assert_eq!(gpio[offsets::FLAGS], 0b10_0001);
+        // The end of the magic was in the first slot's rumble bytes, which mustn't be
+        // mistaken for rumble, now or later.
+        assert_eq!(gpio[offsets::LO_FREQ_RUMBLE], 0);
+        assert_eq!(gpio[offsets::HI_FREQ_RUMBLE], 0);
+        assert_eq!(source.rumble, (0, 0));
+        let sync = sync_gpio(
+            &mut gpio,
+            Some(&sync.written),
+            [Some(&mut source as &mut dyn GamepadSource)],
+        )
+        .unwrap();
+        assert!(!sync.handshake);
+        assert_eq!(source.rumble, (0, 0));
+
+        // Until the cartridge rumbles, which it may do one motor at a time.
+        gpio[offsets::LO_FREQ_RUMBLE] = 0x40;
+        sync_gpio(
+            &mut gpio,
+            Some(&sync.written),
+            [Some(&mut source as &mut dyn GamepadSource)],
+        )
+        .unwrap();
+        assert_eq!(source.rumble, (0x40, 0));
+    }
+
+    #[test]
+    fn handshake_leaves_cart_rumble_alone() {
+        let mut gpio = [0u8; PINPUT_GPIO_SIZE];
+        write_magic(&mut gpio);
+        for record in gpio.chunks_exact_mut(PINPUT_GAMEPAD_SIZE).skip(1) {
+            record[offsets::LO_FREQ_RUMBLE] = 0x11;
+            record[offsets::HI_FREQ_RUMBLE] = 0x22;
+        }
+        let before = gpio;
+
+        let sync = sync_gpio(&mut gpio, None, std::iter::empty()).unwrap();
+
+        assert!(sync.handshake);
+        for record in 1..PINPUT_MAX_GAMEPADS {
+            let rumble = record * PINPUT_GAMEPAD_SIZE + offsets::LO_FREQ_RUMBLE;
+            assert_eq!(gpio[rumble..rumble + 2], before[rumble..rumble + 2]);
+        }
+    }
+
+    #[test]
+    fn rumble_is_read_back_and_preserved() {
+        let mut gpio = [0u8; PINPUT_GPIO_SIZE];
+        gpio[offsets::LO_FREQ_RUMBLE] = 0x40;
+        gpio[offsets::HI_FREQ_RUMBLE] = 0x80;
+        gpio[PINPUT_GAMEPAD_SIZE + offsets::HI_FREQ_RUMBLE] = 0xff;
+        let mut source = mock();
+
+        let sync = sync_gpio(
+            &mut gpio,
+            None,
+            [Some(&mut source as &mut dyn GamepadSource)],
+        )
+        .unwrap();
+
+        assert!(!sync.handshake);
+        assert_eq!(source.rumble, (0x40, 0x80));
+        assert_eq!(gpio[offsets::LO_FREQ_RUMBLE], 0x40);
+        assert_eq!(gpio[offsets::HI_FREQ_RUMBLE], 0x80);
+        assert_eq!(gpio[PINPUT_GAMEPAD_SIZE + offsets::HI_FREQ_RUMBLE], 0xff);
+    }
+
+    #[test]
+    fn sources_are_written_to_their_slots() {
+        let mut gpio = [0xffu8; PINPUT_GPIO_SIZE];
+        let mut source = mock();
+
+        sync_gpio(
+            &mut gpio,
+            None,
+            [None, Some(&mut source as &mut dyn GamepadSource)],
+        )
+        .unwrap();
+
+        // Slot 0 has no source, so it's disconnected.
+        assert_eq!(gpio[offsets::FLAGS], 0);
+        assert_eq!(gpio[offsets::BUTTONS..offsets::BUTTONS + 2], [0, 0]);
+        // Slot 1 has the mock.
+        let slot = &gpio[PINPUT_GAMEPAD_SIZE..2 * PINPUT_GAMEPAD_SIZE];
+        assert_eq!(slot[offsets::BUTTONS..offsets::BUTTONS + 2], [0x00, 0x10]);
+        assert_eq!(
+            slot[offsets::LEFT_STICK_X..offsets::LEFT_STICK_X + 2],
+            [0x34, 0x12]
+        );
+    }
+
+    #[test]
+    fn only_host_bytes_are_compared() {
+        let mut gpio = [0u8; PINPUT_GPIO_SIZE];
+        let written = gpio;
+        // The cartridge changes rumble.
+        gpio[PINPUT_GAMEPAD_SIZE + offsets::LO_FREQ_RUMBLE] = 0x40;
+        assert!(host_bytes_match(&gpio, &written));
+        // Something else changes buttons.
+        gpio[PINPUT_GAMEPAD_SIZE + offsets::BUTTONS] = 0x01;
+        assert!(!host_bytes_match(&gpio, &written));
+    }
+
+    #[test]
+    fn unchanged_slots_are_not_written() {
+        let mut gpio = [0u8; PINPUT_GPIO_SIZE];
+        let mut source = mock();
+
+        let first = sync_gpio(
+            &mut gpio,
+            None,
+            [Some(&mut source as &mut dyn GamepadSource)],
+        )
+        .unwrap();
+        assert_eq!(
+            first.bytes_written,
+            PINPUT_MAX_GAMEPADS * offsets::LO_FREQ_RUMBLE
+        );
+
+        let idle = sync_gpio(
+            &mut gpio,
+            Some(&first.written),
+            [Some(&mut source as &mut dyn GamepadSource)],
+        )
+        .unwrap();
+        assert_eq!(idle.bytes_written, 0);
+
+        source.state.buttons = PinputGamepadButtons::B;
+        let pressed = sync_gpio(
+            &mut gpio,
+            Some(&idle.written),
+            [Some(&mut source as &mut dyn GamepadSource)],
+        )
+        .unwrap();
+        assert_eq!(pressed.bytes_written, offsets::LO_FREQ_RUMBLE);
+        assert_eq!(gpio, pressed.written);
+    }
+
+    #[test]
+    fn write_rate_is_averaged_over_a_second() {
+        let start = Instant::now();
+        let mut rate = WriteRate::new(start);
+        for frame in 1..=60 {
+            rate.record(14, start + Duration::from_millis(frame * 1000 / 60));
+        }
+        assert_eq!(rate.per_second(), 14 * 60);
+        // Idle frames still close the window.
+        for frame in 61..=120 {
+            rate.record(0, start + Duration::from_millis(frame * 1000 / 60));
+        }
+        assert_eq!(rate.per_second(), 0);
+    }
+
+    /// A runtime whose cartridge changes rumble in every slot right after we read GPIO,
+    /// before we get to write it back.
+    #[derive(Debug)]
+    struct RumblingGpio {
+        gpio: [u8; PINPUT_GPIO_SIZE],
+        rumble: u8,
+    }
+
+    impl GpioBackend for RumblingGpio {
+        fn has_magic(&mut self) -> io::Result<bool> {
+            Ok(has_magic(&self.gpio))
+        }
+
+        fn read(&mut self) -> io::Result<[u8; PINPUT_GPIO_SIZE]> {
+            let snapshot = self.gpio;
+            self.rumble = self.rumble.wrapping_add(1);
+            for record in self.gpio.chunks_exact_mut(PINPUT_GAMEPAD_SIZE) {
+                record[offsets::LO_FREQ_RUMBLE] = self.rumble;
+                record[offsets::HI_FREQ_RUMBLE] = !self.rumble;
+            }
+            Ok(snapshot)
+        }
+
+        fn write_spans(
+            &mut self,
+            gpio: &[u8; PINPUT_GPIO_SIZE],
+            spans: &[Range<usize>],
+        ) -> io::Result<()> {
+            self.gpio.write_spans(gpio, spans)
+        }
+    }
+
+    #[test]
+    fn rumble_written_during_a_frame_survives() {
+        let mut gpio = RumblingGpio {
+            gpio: [0; PINPUT_GPIO_SIZE],
+            rumble: 0,
+        };
+        let mut source = mock();
+        for _ in 0..3 {
+            let sync = sync_gpio(
+                &mut gpio,
+                None,
+                [Some(&mut source as &mut dyn GamepadSource)],
+            )
+            .unwrap();
+            assert!(host_bytes_match(&gpio.gpio, &sync.written));
+            for record in gpio.gpio.chunks_exact(PINPUT_GAMEPAD_SIZE) {
+                assert_eq!(record[offsets::LO_FREQ_RUMBLE], gpio.rumble);
+                assert_eq!(record[offsets::HI_FREQ_RUMBLE], !gpio.rumble);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn proc_mem_leaves_rumble_to_a_running_cart() {
+        use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+
+        // Atomics have the same layout as bytes, and let the cart thread share them with the kernel.
+        let runtime_gpio: Arc<[AtomicU8; PINPUT_GPIO_SIZE]> =
+            Arc::new(std::array::from_fn(|_| AtomicU8::new(0)));
+        let address = runtime_gpio.as_ptr() as usize;
+        let mut gpio =
+            ProcMemGpio::open(std::process::id() as process_memory::Pid, address).unwrap();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let cart = {
+            let runtime_gpio = runtime_gpio.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut clobbered = 0;
+                for rumble in (0..=u8::MAX).cycle().take(20_000) {
+                    for span in host_spans() {
+                        runtime_gpio[span.end].store(rumble, Ordering::SeqCst);
+                    }
+                    for span in host_spans() {
+                        if runtime_gpio[span.end].load(Ordering::SeqCst) != rumble {
+                            clobbered += 1;
+                        }
+                    }
+                }
+                done.store(true, Ordering::SeqCst);
+                clobbered
+            })
+        };
+
+        let mut source = mock();
+        while !done.load(Ordering::SeqCst) {
+            sync_gpio(
+                &mut gpio,
+                None,
+                [Some(&mut source as &mut dyn GamepadSource)],
+            )
+            .unwrap();
+        }
+        assert_eq!(cart.join().unwrap(), 0);
+    }
+
+    /// A runtime that has already gone away.
+    #[derive(Debug)]
+    struct VanishedGpio;
+
+    fn vanished() -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, "runtime exited")
+    }
+
+    impl GpioBackend for VanishedGpio {
+        fn has_magic(&mut self) -> io::Result<bool> {
+            Err(vanished())
+        }
+
+        fn read(&mut self) -> io::Result<[u8; PINPUT_GPIO_SIZE]> {
+            Err(vanished())
+        }
+
+        fn write_spans(
+            &mut self,
+            _gpio: &[u8; PINPUT_GPIO_SIZE],
+            _spans: &[Range<usize>],
+        ) -> io::Result<()> {
+            Err(vanished())
+        }
+    }
+
+    #[test]
+    fn gpio_errors_are_reported() {
+        let result = sync_gpio(&mut VanishedGpio, None, std::iter::empty());
+        assert!(matches!(result, Err(Error::Gpio(_))));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn proc_mem_reads_and_writes_our_own_gpio() {
+        let mut runtime_gpio = Box::new([0u8; PINPUT_GPIO_SIZE]);
+        write_magic(&mut *runtime_gpio);
+        let address = runtime_gpio.as_ptr() as usize;
+        let mut gpio =
+            ProcMemGpio::open(std::process::id() as process_memory::Pid, address).unwrap();
+
+        let mut source = mock();
+        let sync = sync_gpio(
+            &mut gpio,
+            None,
+            [Some(&mut source as &mut dyn GamepadSource)],
+        )
+        .unwrap();
+
+        assert!(sync.handshake);
+        // The write went around the compiler's back.
+        let written = unsafe { std::ptr::read_volatile(&*runtime_gpio) };
+        assert!(host_bytes_match(&written, &sync.written));
+        assert!(!gpio.has_magic().unwrap());
+
+        // A cartridge that restarts writes magic again, and the next check must see it.
+        let mut restarted = written;
+        write_magic(&mut restarted);
+        unsafe { std::ptr::write_volatile(&mut *runtime_gpio, restarted) };
+        assert!(gpio.has_magic().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn proc_mem_writes_only_the_spans_given() {
+        let runtime_gpio = Box::new([0xffu8; PINPUT_GPIO_SIZE]);
+        let address = runtime_gpio.as_ptr() as usize;
+        let mut gpio =
+            ProcMemGpio::open(std::process::id() as process_memory::Pid, address).unwrap();
+        let spans = [16..30, 64..78];
+
+        gpio.write_spans(&[0; PINPUT_GPIO_SIZE], &spans).unwrap();
+
+        let written = unsafe { std::ptr::read_volatile(&*runtime_gpio) };
+        for (i, byte) in written.iter().enumerate() {
+            let expected = if spans.iter().any(|span| span.contains(&i)) {
+                0
+            } else {
+                0xff
+            };
+            assert_eq!(*byte, expected, "byte {i}");
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn proc_mem_reports_exited_processes() {
+        use crate::runtime_connection::is_no_such_process;
+
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let mut gpio = ProcMemGpio::open(child.id() as process_memory::Pid, 0x1000).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        assert!(is_no_such_process(&gpio.read().unwrap_err()));
+        assert!(is_no_such_process(
+            &gpio.write(&[0; PINPUT_GPIO_SIZE]).unwrap_err()
+        ));
+    }
+}
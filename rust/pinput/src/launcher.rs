@@ -0,0 +1,94 @@
+//! Starting a runtime with a cartridge ourselves, instead of finding one that's already running.
+//! A parent process may read its children's memory under Linux's default Yama `ptrace_scope`,
+//! so this works without `CAP_SYS_PTRACE`.
+
+use std::ffi::OsStr;
+#[cfg(unix)]
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+
+use crate::error::Error;
+
+/// Which runtime runs a cartridge file, going by its name,
+/// and the arguments it needs to start running the cartridge right away.
+fn runtime_for(cart: &Path) -> Option<(&'static str, Vec<&'static OsStr>)> {
+    let name = cart.file_name()?.to_string_lossy().to_lowercase();
+    if [".p8", ".p8.png", ".p8.rom"]
+        .iter()
+        .any(|extension| name.ends_with(extension))
+    {
+        Some(("pico8", vec![OsStr::new("-run")]))
+    } else if name.ends_with(".wasm") {
+        // The native runtime, not the `w4` CLI, which would start it as a grandchild.
+        Some(("wasm4", Vec::new()))
+    } else if name.ends_with(".tic") {
+        Some(("tic80", Vec::new()))
+    } else {
+        None
+    }
+}
+
+/// Command to run `cart` in the runtime that runs it, or in `runtime` if given.
+/// Without `runtime`, the runtime's executable is looked up on `PATH`.
+pub fn runtime_command(cart: &Path, runtime: Option<&Path>) -> Result<Command, Error> {
+    let (executable, args) =
+        runtime_for(cart).ok_or_else(|| Error::UnknownCartType(cart.to_path_buf()))?;
+    let mut command =
+        Command::new(runtime.map_or_else(|| PathBuf::from(executable), Path::to_path_buf));
+    command.args(args).arg(cart);
+    Ok(command)
+}
+
+/// Exit code for Pinput that passes on how the runtime exited.
+/// Runtimes killed by a signal get 128 plus the signal number, as in a shell.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    if let Some(signal) = status.signal() {
+        return 128 + signal;
+    }
+    status.code().unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program_and_args(command: &Command) -> Vec<&OsStr> {
+        std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .collect()
+    }
+
+    #[test]
+    fn carts_pick_their_runtime() {
+        let command = runtime_command(Path::new("games/Celeste.P8.png"), None).unwrap();
+        assert_eq!(
+            program_and_args(&command),
+            ["pico8", "-run", "games/Celeste.P8.png"]
+        );
+
+        let command = runtime_command(
+            Path::new("cart.wasm"),
+            Some(Path::new("/opt/wasm4/wasm4_glfw")),
+        )
+        .unwrap();
+        assert_eq!(
+            program_and_args(&command),
+            ["/opt/wasm4/wasm4_glfw", "cart.wasm"]
+        );
+
+        assert!(matches!(
+            runtime_command(Path::new("notes.txt"), None),
+            Err(Error::UnknownCartType(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_codes_pass_through() {
+        assert_eq!(exit_code(ExitStatus::from_raw(3 << 8)), 3);
+        // Killed by SIGKILL.
+        assert_eq!(exit_code(ExitStatus::from_raw(9)), 128 + 9);
+    }
+}
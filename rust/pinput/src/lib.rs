@@ -0,0 +1,37 @@
+//! Pinput connects gamepads and haptic devices to PICO-8, WASM-4, and TIC-80 runtimes
+//! by reading and writing a 128-byte GPIO block in the runtime's memory.
+//!
+//! [`Bridge`] is the embeddable entry point; the `pinput` binary is a thin CLI over it.
+//! Both need the default `bridge` feature. Without it, this is just the memory access
+//! that the `pinput-helper` crate needs, without SDL or Tokio.
+
+#[cfg(feature = "bridge")]
+pub mod bridge;
+pub mod constants;
+pub mod error;
+#[cfg(not(target_os = "macos"))]
+pub mod exe_inspector;
+#[cfg(feature = "bridge")]
+pub mod gamepad;
+pub mod gamepad_source;
+pub mod gpio_backend;
+#[cfg(feature = "haptics")]
+pub mod haptic_subsystem;
+pub mod launcher;
+pub mod magic_scanner;
+#[cfg(target_os = "linux")]
+pub mod memory_helper;
+pub mod runtime_connection;
+pub mod runtime_definition;
+pub mod runtime_policy;
+pub mod slots;
+#[cfg(target_os = "linux")]
+pub mod wine;
+
+#[cfg(feature = "bridge")]
+pub use crate::bridge::{Bridge, BridgeOptions};
+pub use crate::error::Error;
+pub use crate::gamepad_source::GamepadSource;
+pub use crate::gpio_backend::GpioBackend;
+pub use crate::runtime_definition::RuntimeDefinition;
+pub use crate::runtime_policy::RuntimePolicy;
@@ -0,0 +1,554 @@
+//! Searching runtime memory for Pinput magic a chunk at a time,
+//! without copying whole mappings or rescanning ones we've already been through.
+
+use memchr::memmem;
+use pinput_protocol::PINPUT_MAGIC;
+use proc_maps::MapRange;
+use process_memory::Pid;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+
+use crate::constants::SCAN_CHUNK_SIZE;
+use crate::runtime_connection::is_no_such_process;
+
+/// A mapping in a runtime's memory, as much as we need to tell if it's changed between scans.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Region {
+    pub start: usize,
+    pub size: usize,
+    pub path: Option<PathBuf>,
+}
+
+impl Region {
+    /// Are all `len` bytes starting at `address` inside this region?
+    pub fn contains(&self, address: usize, len: usize) -> bool {
+        address >= self.start
+            && address
+                .checked_add(len)
+                .is_some_and(|end| end <= self.start.saturating_add(self.size))
+    }
+}
+
+impl From<&MapRange> for Region {
+    fn from(map: &MapRange) -> Self {
+        Region {
+            start: map.start(),
+            size: map.size(),
+            path: map.filename().map(|path| path.to_path_buf()),
+        }
+    }
+}
+
+/// Reading memory failed in a way that would fail for every region,
+/// like not being allowed to read the process's memory at all, so the scan stopped there.
+#[derive(Debug)]
+pub struct ScanError {
+    pub address: usize,
+    pub source: io::Error,
+}
+
+/// How far we've got through one region.
+#[derive(Debug, Default)]
+struct RegionScan {
+    /// Offset of the next chunk to read.
+    cursor: usize,
+    /// Scanned all the way through at least once without finding magic.
+    scanned: bool,
+    /// Reading it failed like this, so don't try again unless the mapping changes.
+    error: Option<io::Error>,
+    /// Pass in which we last finished scanning it, so unchanged regions take turns being rescanned.
+    finished_pass: u64,
+}
+
+/// Everything we know about one process that we haven't found GPIO in yet.
+#[derive(Debug, Default)]
+struct ProcessScan {
+    regions: HashMap<Region, RegionScan>,
+    /// Addresses of magic that could be the GPIO block, in the order we found them.
+    candidates: Vec<usize>,
+    /// Addresses of magic we've already logged as not being the GPIO block.
+    rejected: HashSet<usize>,
+}
+
+/// Remembers what's been scanned in each process between scans,
+/// and limits how much memory gets read in each one.
+#[derive(Debug)]
+pub struct MagicScanner {
+    /// Bytes to read per pass, or `None` for no limit.
+    budget: Option<usize>,
+    /// Bytes left to read in this pass.
+    remaining: Option<usize>,
+    chunk_size: usize,
+    pass: u64,
+    processes: HashMap<Pid, ProcessScan>,
+}
+
+impl MagicScanner {
+    /// Read at most `budget` bytes per pass.
+    pub fn new(budget: usize) -> Self {
+        Self::with_budget(Some(budget))
+    }
+
+    /// Read as much as it takes, for one-off searches.
+    pub fn unbounded() -> Self {
+        Self::with_budget(None)
+    }
+
+    fn with_budget(budget: Option<usize>) -> Self {
+        MagicScanner {
+            budget,
+            remaining: budget,
+            chunk_size: SCAN_CHUNK_SIZE,
+            pass: 0,
+            processes: HashMap::new(),
+        }
+    }
+
+    /// Start a new pass with a fresh budget, shared by every process searched until the next one.
+    /// Forgets processes that aren't in `live`.
+    pub fn start_pass(&mut self, live: &[Pid]) {
+        self.pass += 1;
+        self.remaining = self.budget;
+        self.processes.retain(|pid, _| live.contains(pid));
+    }
+
+    /// Search a process's `regions` for Pinput magic, reading memory with `read(address, len)`.
+    ///
+    /// Regions we haven't finished scanning go first, picking up where the last pass left off.
+    /// Regions that were already scanned without finding magic are only rescanned with budget to spare,
+    /// least recently scanned first, in case a cartridge has written magic there since.
+    /// Regions that couldn't be read are skipped until they change,
+    /// unless the error means we can't read any of them, which stops the scan.
+    ///
+    /// Stray copies of the magic can turn up anywhere, so every hit is rated with
+    /// `score(address, region)`, which returns a reason instead for hits that can't be the GPIO block.
+    /// A hit scoring `perfect` can't be beaten, so it wins as soon as it's found.
+    /// Otherwise, once every region has been scanned, the best-scoring hit wins,
+    /// or the first one found if there's a tie. Rejected hits are logged.
+    pub fn find(
+        &mut self,
+        pid: Pid,
+        regions: impl IntoIterator<Item = Region>,
+        mut read: impl FnMut(usize, usize) -> io::Result<Vec<u8>>,
+        mut score: impl FnMut(usize, &Region) -> Result<u32, String>,
+        perfect: u32,
+    ) -> Result<Option<usize>, ScanError> {
+        let mut process = self.processes.remove(&pid).unwrap_or_default();
+        // Regions that have gone away are dropped, and changed ones start over.
+        let mut old = std::mem::take(&mut process.regions);
+        process.regions = regions
+            .into_iter()
+            .map(|region| {
+                let scan = old.remove(&region).unwrap_or_default();
+                (region, scan)
+            })
+            .collect();
+
+        let mut order: Vec<Region> = process
+            .regions
+            .iter()
+            .filter(|(_, scan)| scan.error.is_none())
+            .map(|(region, _)| region.clone())
+            .collect();
+        order.sort_by_key(|region| {
+            let scan = &process.regions[region];
+            (scan.scanned, scan.finished_pass, region.start)
+        });
+
+        for region in order {
+            let scan = process
+                .regions
+                .get_mut(&region)
+                .expect("ordered regions come from scans");
+            // Score hits as each chunk turns them up, in case one is perfect.
+            loop {
+                let mut hits = Vec::new();
+                let result = self.scan_region(&region, scan, &mut read, &mut hits);
+                for address in hits {
+                    match score(address, &region) {
+                        Ok(points) if points >= perfect => {
+                            let others = std::mem::take(&mut process.candidates)
+                                .into_iter()
+                                .filter(|&candidate| candidate != address);
+                            for candidate in others {
+                                let reason = format!("{address:#x} looks exactly like GPIO");
+                                reject(&mut process.rejected, pid, candidate, &reason);
+                            }
+                            return Ok(Some(address));
+                        }
+                        Ok(_) if process.candidates.contains(&address) => {}
+                        Ok(_) => process.candidates.push(address),
+                        Err(reason) => reject(&mut process.rejected, pid, address, &reason),
+                    }
+                }
+                if let Err(err) = result {
+                    if is_process_wide(&err) {
+                        return Err(ScanError {
+                            address: region.start + scan.cursor,
+                            source: err,
+                        });
+                    }
+                    scan.error = Some(err);
+                }
+                // A cursor of zero means the region is done.
+                if scan.error.is_some() || scan.cursor == 0 || self.remaining == Some(0) {
+                    break;
+                }
+            }
+            if self.remaining == Some(0) {
+                break;
+            }
+        }
+
+        let settled = process
+            .regions
+            .values()
+            .all(|scan| scan.scanned || scan.error.is_some());
+        if settled {
+            if let Some(address) = process.choose(pid, &mut read, &mut score) {
+                return Ok(Some(address));
+            }
+        }
+
+        self.processes.insert(pid, process);
+        Ok(None)
+    }
+
+    /// Why one of the process's regions couldn't be read, if any couldn't,
+    /// since the magic could be in one of those.
+    pub fn read_error(&self, pid: Pid) -> Option<io::Error> {
+        let err = self
+            .processes
+            .get(&pid)?
+            .regions
+            .values()
+            .find_map(|scan| scan.error.as_ref())?;
+        Some(match err.raw_os_error() {
+            Some(code) => io::Error::from_raw_os_error(code),
+            None => io::Error::new(err.kind(), err.to_string()),
+        })
+    }
+
+    /// Read a region in chunks, starting at its cursor, until we run out of region or budget
+    /// or find magic, adding the address of each copy of magic to `hits`.
+    /// Chunks overlap by one byte less than the magic so that magic split across two chunks is still found.
+    fn scan_region(
+        &mut self,
+        region: &Region,
+        scan: &mut RegionScan,
+        read: &mut impl FnMut(usize, usize) -> io::Result<Vec<u8>>,
+        hits: &mut Vec<usize>,
+    ) -> io::Result<()> {
+        let overlap = PINPUT_MAGIC.len() - 1;
+        while scan.cursor < region.size {
+            let mut len = self.chunk_size.min(region.size - scan.cursor);
+            if let Some(remaining) = self.remaining {
+                let reaches_end = scan.cursor + remaining >= region.size;
+                if remaining < PINPUT_MAGIC.len() && !reaches_end {
+                    self.remaining = Some(0);
+                    return Ok(());
+                }
+                len = len.min(remaining);
+                self.remaining = Some(remaining - len);
+            }
+
+            let data = read(region.start + scan.cursor, len)?;
+            hits.extend(
+                memmem::find_iter(&data, &PINPUT_MAGIC)
+                    .map(|offset| region.start + scan.cursor + offset),
+            );
+
+            if scan.cursor + len >= region.size {
+                scan.cursor = region.size;
+            } else {
+                scan.cursor += len - overlap;
+                if self.remaining == Some(0) || !hits.is_empty() {
+                    return Ok(());
+                }
+            }
+        }
+
+        scan.cursor = 0;
+        scan.scanned = true;
+        scan.finished_pass = self.pass;
+        Ok(())
+    }
+}
+
+impl ProcessScan {
+    /// Pick the best of the candidates, after checking that they haven't changed since we found them.
+    fn choose(
+        &mut self,
+        pid: Pid,
+        read: &mut impl FnMut(usize, usize) -> io::Result<Vec<u8>>,
+        score: &mut impl FnMut(usize, &Region) -> Result<u32, String>,
+    ) -> Option<usize> {
+        let mut scored = Vec::new();
+        for address in std::mem::take(&mut self.candidates) {
+            let result = match self
+                .regions
+                .keys()
+                .find(|region| region.contains(address, PINPUT_MAGIC.len()))
+            {
+                None => Err("its mapping has gone away".to_owned()),
+                Some(region) => match read(address, PINPUT_MAGIC.len()) {
+                    Ok(data) if data == PINPUT_MAGIC => score(address, region),
+                    _ => Err("it's been overwritten".to_owned()),
+                },
+            };
+            match result {
+                Ok(points) => scored.push((address, points)),
+                Err(reason) => reject(&mut self.rejected, pid, address, &reason),
+            }
+        }
+
+        let (best, best_points) =
+            scored
+                .iter()
+                .copied()
+                .reduce(|best, hit| if hit.1 > best.1 { hit } else { best })?;
+        for &(address, points) in &scored {
+            if address != best {
+                let reason = format!(
+                    "it looks less like GPIO ({points} points) than {best:#x} ({best_points} points)"
+                );
+                reject(&mut self.rejected, pid, address, &reason);
+            }
+        }
+        Some(best)
+    }
+}
+
+/// Errors that mean we can't read the process's memory at all.
+fn is_process_wide(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::PermissionDenied || is_no_such_process(err)
+}
+
+/// Log a hit that isn't the GPIO block, unless we already have.
+fn reject(rejected: &mut HashSet<usize>, pid: Pid, address: usize, reason: &str) {
+    if rejected.insert(address) {
+        println!("Ignoring Pinput magic at {address:#x} in PID {pid}: {reason}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Fake memory starting at 0x1000, with magic at each of `offsets`.
+    fn memory(size: usize, offsets: &[usize]) -> Vec<u8> {
+        let mut memory = vec![0u8; size];
+        for &offset in offsets {
+            memory[offset..offset + PINPUT_MAGIC.len()].copy_from_slice(&PINPUT_MAGIC);
+        }
+        memory
+    }
+
+    fn region(start: usize, size: usize) -> Region {
+        Region {
+            start,
+            size,
+            path: None,
+        }
+    }
+
+    fn reader<'a>(
+        memory: &'a [u8],
+        bytes_read: &'a Cell<usize>,
+    ) -> impl FnMut(usize, usize) -> io::Result<Vec<u8>> + 'a {
+        move |address, len| {
+            bytes_read.set(bytes_read.get() + len);
+            let offset = address - 0x1000;
+            Ok(memory[offset..offset + len].to_vec())
+        }
+    }
+
+    fn any_hit(_address: usize, _region: &Region) -> Result<u32, String> {
+        Ok(0)
+    }
+
+    /// No hit is good enough to stop searching early.
+    const NO_PERFECT: u32 = u32::MAX;
+
+    fn scanner(budget: Option<usize>) -> MagicScanner {
+        MagicScanner {
+            chunk_size: 64,
+            ..MagicScanner::with_budget(budget)
+        }
+    }
+
+    #[test]
+    fn finds_magic_split_across_chunks() {
+        let memory = memory(256, &[60]);
+        let bytes_read = Cell::new(0);
+        let mut scanner = scanner(None);
+        let found = scanner.find(
+            1,
+            [region(0x1000, 256)],
+            reader(&memory, &bytes_read),
+            any_hit,
+            NO_PERFECT,
+        );
+        assert_eq!(found.unwrap(), Some(0x1000 + 60));
+    }
+
+    #[test]
+    fn budget_spreads_a_region_over_several_passes() {
+        let memory = memory(256, &[200]);
+        let bytes_read = Cell::new(0);
+        let mut scanner = scanner(Some(100));
+        let find = |scanner: &mut MagicScanner| {
+            scanner.start_pass(&[1]);
+            scanner
+                .find(
+                    1,
+                    [region(0x1000, 256)],
+                    reader(&memory, &bytes_read),
+                    any_hit,
+                    NO_PERFECT,
+                )
+                .unwrap()
+        };
+
+        assert_eq!(find(&mut scanner), None);
+        assert_eq!(bytes_read.get(), 100);
+        assert_eq!(find(&mut scanner), None);
+        // The magic turns up in this pass, but there's still more of the region to search.
+        assert_eq!(find(&mut scanner), None);
+        assert_eq!(find(&mut scanner), Some(0x1000 + 200));
+    }
+
+    #[test]
+    fn unchanged_regions_wait_for_spare_budget() {
+        let empty = memory(128, &[]);
+        let bytes_read = Cell::new(0);
+        let mut scanner = scanner(Some(160));
+
+        scanner.start_pass(&[1]);
+        scanner
+            .find(
+                1,
+                [region(0x1000, 128)],
+                reader(&empty, &bytes_read),
+                any_hit,
+                NO_PERFECT,
+            )
+            .unwrap();
+        // Overlapping chunks take a little more than the region's size.
+        assert_eq!(bytes_read.get(), 158);
+
+        // A new region appears after the one we've scanned, and gets the whole budget.
+        let memory = memory(256, &[200]);
+        scanner.start_pass(&[1]);
+        let found = scanner.find(
+            1,
+            [region(0x1000, 128), region(0x1080, 128)],
+            reader(&memory, &bytes_read),
+            any_hit,
+            NO_PERFECT,
+        );
+        assert_eq!(found.unwrap(), Some(0x1000 + 200));
+        // Plus checking that the magic is still there before picking it.
+        assert_eq!(bytes_read.get(), 158 + 158 + PINPUT_MAGIC.len());
+    }
+
+    #[test]
+    fn failed_regions_are_skipped_until_they_change() {
+        let attempts = Cell::new(0);
+        let mut failing = |_address: usize, _len: usize| -> io::Result<Vec<u8>> {
+            attempts.set(attempts.get() + 1);
+            Err(io::Error::other("bad address"))
+        };
+        let mut scanner = scanner(None);
+        let mut find = |scanner: &mut MagicScanner, size| {
+            scanner.start_pass(&[1]);
+            scanner
+                .find(1, [region(0x1000, size)], &mut failing, any_hit, NO_PERFECT)
+                .unwrap()
+        };
+
+        assert_eq!(find(&mut scanner, 64), None);
+        assert_eq!(find(&mut scanner, 64), None);
+        assert_eq!(attempts.get(), 1);
+        assert_eq!(scanner.read_error(1).unwrap().to_string(), "bad address");
+
+        assert_eq!(find(&mut scanner, 128), None);
+        assert_eq!(attempts.get(), 2);
+
+        // Forgetting the process forgets the failure.
+        scanner.start_pass(&[]);
+        assert_eq!(find(&mut scanner, 128), None);
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn best_scoring_hit_wins() {
+        // A stray copy, the real GPIO, and one that can't be GPIO.
+        let memory = memory(512, &[16, 256, 480]);
+        let bytes_read = Cell::new(0);
+        let mut scanner = scanner(None);
+        let found = scanner.find(
+            1,
+            [region(0x1000, 512)],
+            reader(&memory, &bytes_read),
+            |address, region| match address - region.start {
+                256 => Ok(2),
+                480 => Err("too close to the end".to_owned()),
+                _ => Ok(1),
+            },
+            NO_PERFECT,
+        );
+        assert_eq!(found.unwrap(), Some(0x1000 + 256));
+    }
+
+    #[test]
+    fn perfect_hit_wins_right_away() {
+        // A stray copy, then the real GPIO early in a big region, then more stray copies.
+        let memory = memory(1024, &[16, 100, 900]);
+        let bytes_read = Cell::new(0);
+        let mut scanner = scanner(Some(256));
+        scanner.start_pass(&[1]);
+        let found = scanner.find(
+            1,
+            [region(0x1000, 1024)],
+            reader(&memory, &bytes_read),
+            |address, region| Ok(if address - region.start == 100 { 2 } else { 1 }),
+            2,
+        );
+        assert_eq!(found.unwrap(), Some(0x1000 + 100));
+        // Stopped at the chunk with the magic in it, well short of the budget.
+        assert!(bytes_read.get() < 256, "read {} bytes", bytes_read.get());
+    }
+
+    #[test]
+    fn permission_errors_stop_the_scan() {
+        let attempts = Cell::new(0);
+        let denied = |_address: usize, _len: usize| -> io::Result<Vec<u8>> {
+            attempts.set(attempts.get() + 1);
+            Err(io::ErrorKind::PermissionDenied.into())
+        };
+        let mut scanner = scanner(None);
+        let result = scanner.find(
+            1,
+            [region(0x1000, 64), region(0x2000, 64)],
+            denied,
+            any_hit,
+            NO_PERFECT,
+        );
+        let err = result.unwrap_err();
+        assert_eq!(err.address, 0x1000);
+        assert_eq!(err.source.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn region_bounds() {
+        let region = region(0x1000, 0x1000);
+        assert!(region.contains(0x1000, 0x1000));
+        assert!(!region.contains(0x1000, 0x1001));
+        assert!(!region.contains(0xfff, 16));
+        assert!(!region.contains(usize::MAX, 16));
+    }
+}
@@ -1,179 +1,42 @@
-#[cfg(target_os = "linux")]
-use caps;
-use chrono::Duration;
-use ctrlc;
-use process_memory::Memory;
-use sdl2;
-use sdl2::{GameControllerSubsystem, JoystickSubsystem};
-use std::cmp::min;
 use std::env;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::channel;
-use std::sync::Arc;
-use timer::Timer;
-
-mod constants;
-mod error;
-mod gamepad;
-#[cfg(feature = "haptics")]
-mod haptic_subsystem;
-mod runtime_connection;
+use std::io;
+use std::path::PathBuf;
+use std::process::{self, ExitStatus};
 
-use crate::constants::{FRAME_DURATION_MS, PINPUT_MAGIC, PINPUT_MAX_GAMEPADS, SCAN_INTERVAL_MS};
-use crate::error::Error;
-use crate::gamepad::{sync_gamepad, PinputGamepadArray, SdlGamepad};
-#[cfg(feature = "haptics")]
-use crate::haptic_subsystem::HapticSubsystem;
-use crate::runtime_connection::RuntimeConnection;
+use pinput::launcher::{exit_code, runtime_command};
+#[cfg(target_os = "linux")]
+use pinput::{
+    memory_helper::{has_ptrace_capability, helper_path},
+    runtime_connection::yama_ptrace_scope,
+};
+use pinput::{Bridge, BridgeOptions, Error, RuntimePolicy};
+use process_memory::Pid;
+use tokio::process::{Child, Command};
 
-/// Look for a runtime with Pinput magic until we either find it or are killed.
-fn scan_for_runtime_connection(keep_going: Arc<AtomicBool>) -> Result<RuntimeConnection, Error> {
-    let (timer_tx, timer_rx) = channel();
-    let timer = Timer::new();
-    let _timer_guard = Some(timer.schedule_repeating(
-        Duration::milliseconds(SCAN_INTERVAL_MS),
-        move || {
-            timer_tx
-                .send(())
-                .expect("we should always be able to send timer ticks")
-        },
-    ));
-    while keep_going.load(Ordering::Relaxed) {
-        timer_rx.recv()?;
-        match RuntimeConnection::try_new() {
-            Ok(runtime_connection) => return Ok(runtime_connection),
-            Err(err) => println!("Failed to connect to a runtime: {:#?}", err),
-        }
+/// When `launching`, we only need access to our own child.
+#[cfg(target_os = "linux")]
+fn check_prerequisites(launching: bool) -> Result<(), Error> {
+    // Yama's default scope still lets a parent read its children's memory.
+    if launching && yama_ptrace_scope().is_none_or(|scope| scope <= 1) {
+        return Ok(());
     }
-    Err(Error::KilledByCtrlC)
-}
-
-/// Sync SDL gamepads with the runtime until the runtime quits or we are killed.
-fn run_gamepad_loop(
-    keep_going: &Arc<AtomicBool>,
-    joystick_subsystem: &JoystickSubsystem,
-    game_controller_subsystem: &GameControllerSubsystem,
-    #[cfg(feature = "haptics")] haptic_subsystem: &HapticSubsystem,
-    runtime_connection: &RuntimeConnection,
-) -> Result<(), Error> {
-    let (timer_tx, timer_rx) = channel();
-    let timer = Timer::new();
-    let _timer_guard = Some(timer.schedule_repeating(
-        Duration::milliseconds(FRAME_DURATION_MS),
-        move || {
-            timer_tx
-                .send(())
-                .expect("we should always be able to send timer ticks")
-        },
-    ));
-
-    let mut gamepads: PinputGamepadArray;
-    let mut sdl_gamepads: [Option<SdlGamepad>; PINPUT_MAX_GAMEPADS] = Default::default();
-    while keep_going.load(Ordering::Relaxed) {
-        timer_rx.recv()?;
-
-        // Updates the state of all game controllers.
-        // We could also run the SDL event loop, which would call this automatically.
-        game_controller_subsystem.update();
-
-        let magic = match unsafe { runtime_connection.gpio_as_uuid.read() } {
-            Ok(magic) => magic,
-            Err(err) => {
-                // Failure here probably indicates that the runtime quit.
-                println!(
-                    "Failed to read from {}: {:#}",
-                    runtime_connection.flavor, err
+    // Otherwise the helper does the reading, if it's installed, and it's the one that needs access.
+    if !launching {
+        match helper_path() {
+            Some(helper) if has_ptrace_capability(&helper) => return Ok(()),
+            Some(helper) => {
+                eprintln!(
+                    "{} is missing the CAP_SYS_PTRACE capability, so Pinput won't use it.",
+                    helper.display()
                 );
-                return Ok(());
-            }
-        };
-        if magic == PINPUT_MAGIC {
-            gamepads = PinputGamepadArray::default();
-        } else {
-            gamepads = match unsafe { runtime_connection.gpio_as_gamepads.read() } {
-                Ok(gamepads) => gamepads,
-                Err(err) => {
-                    // Failure here probably indicates that the runtime quit.
-                    println!(
-                        "Failed to read from {}: {:#}",
-                        runtime_connection.flavor, err
-                    );
-                    return Ok(());
-                }
-            }
-        }
-
-        // Handle gamepads.
-        let sdl_num_joysticks = game_controller_subsystem
-            .num_joysticks()
-            .map_err(|s| Error::SdlStringError(s))?;
-        for gamepad_index in 0..min(sdl_num_joysticks as usize, PINPUT_MAX_GAMEPADS) {
-            if sdl_gamepads[gamepad_index].is_none() {
-                let sdl_gamepad_index = gamepad_index as u32;
-                if !game_controller_subsystem.is_game_controller(sdl_gamepad_index) {
-                    continue;
-                }
-                let mut game_controller = game_controller_subsystem.open(sdl_gamepad_index)?;
-
-                // There's no way to test for rumble support other than by trying it.
-                // TODO: this will be fixed in SDL 2.0.18, with `SDL_GameControllerHasRumble`.
-                let has_rumble = game_controller.set_rumble(1, 1, 0).is_ok();
-                println!(
-                    "{} {} rumble.",
-                    game_controller.name(),
-                    if has_rumble {
-                        "supports"
-                    } else {
-                        "doesn't support"
-                    }
+                eprintln!(
+                    "To keep Pinput itself unprivileged, run: sudo setcap cap_sys_ptrace+ep {}",
+                    helper.display()
                 );
-
-                sdl_gamepads[gamepad_index] = Some(SdlGamepad {
-                    joystick: joystick_subsystem.open(sdl_gamepad_index)?,
-                    game_controller,
-                    has_rumble,
-                });
-            }
-
-            let mut gamepad = &mut gamepads[gamepad_index];
-            if let Some(sdl_gamepad) = &mut sdl_gamepads[gamepad_index] {
-                sync_gamepad(sdl_gamepad, &mut gamepad)?;
-            }
-        }
-
-        #[cfg(feature = "haptics")]
-        {
-            // Put haptic devices at the end of the list after gamepads.
-            // TODO: this doesn't make a ton of sense as is since adding gamepads will renumber haptics.
-            // TODO: when fixed, remember to zero out vibe info for Pinput gamepads past the last connected vibe.
-            let haptic_devices = haptic_subsystem.devices();
-            let gamepad_indexes = sdl_num_joysticks as usize
-                ..min(
-                    sdl_num_joysticks as usize + haptic_devices.len(),
-                    PINPUT_MAX_GAMEPADS,
-                );
-            for (gamepad_index, haptic_device) in gamepad_indexes.zip(haptic_devices) {
-                haptic_subsystem.sync_haptic_device(&haptic_device, &mut gamepads[gamepad_index]);
-            }
-        }
-
-        match runtime_connection.gpio_as_gamepads.write(&gamepads) {
-            Ok(_) => (),
-            Err(err) => {
-                // Failure here probably indicates that the runtime quit.
-                println!(
-                    "Failed to write from {}: {:#}",
-                    runtime_connection.flavor, err
-                );
-                return Ok(());
             }
+            None => {}
         }
     }
-    Err(Error::KilledByCtrlC)
-}
-
-#[cfg(target_os = "linux")]
-fn check_prerequisites() -> Result<(), Error> {
     if caps::has_cap(
         None,
         caps::CapSet::Effective,
@@ -191,96 +54,219 @@ fn check_prerequisites() -> Result<(), Error> {
 }
 
 #[cfg(target_os = "macos")]
-fn check_prerequisites() -> Result<(), Error> {
+fn check_prerequisites(_launching: bool) -> Result<(), Error> {
     // TODO: need to be root or in an entitled binary to do this on macOS
     // https://dev.to/jasonelwood/setup-gdb-on-macos-in-2020-489k
     Ok(())
 }
 
 #[cfg(not(any(target_os = "linux", target_os = "macos")))]
-fn check_prerequisites() -> Result<(), Error> {
+fn check_prerequisites(_launching: bool) -> Result<(), Error> {
     Ok(())
 }
 
-fn main() -> Result<(), Error> {
+/// Accept GPIO addresses in hex with a `0x` prefix, as debuggers print them, or in decimal.
+fn parse_address(address: &str) -> Option<usize> {
+    match address.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => address.parse().ok(),
+    }
+}
+
+/// Accept sizes in bytes, or with a `K`, `M`, or `G` suffix for binary kilobytes and so on.
+fn parse_size(size: &str) -> Option<usize> {
+    let (digits, scale) = match size.char_indices().last()? {
+        (i, 'K' | 'k') => (&size[..i], 1 << 10),
+        (i, 'M' | 'm') => (&size[..i], 1 << 20),
+        (i, 'G' | 'g') => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(scale)
+}
+
+/// Per-user state directory on Linux, local app data elsewhere.
+fn default_slot_file() -> Option<PathBuf> {
+    dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .map(|dir| dir.join("pinput").join("slots.json"))
+}
+
+/// Per-user config directory.
+fn default_runtime_file() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("pinput").join("runtimes.json"))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
     // TODO: real command-line parser
     let args: Vec<String> = env::args().collect();
-    let name = if args.len() == 0 {
-        "pinput".to_owned()
-    } else {
-        args[0].clone()
-    };
-    let mut show_usage = false;
-    let expected_lengths = vec![
-        1,
-        #[cfg(feature = "haptics")]
-        3,
-    ];
-    if !expected_lengths.contains(&args.len())
-        || args.contains(&"-h".to_owned())
-        || args.contains(&"--help".to_owned())
-    {
-        show_usage = true;
-    }
+    let name = args.first().cloned().unwrap_or_else(|| "pinput".to_owned());
+    let mut show_usage = args.contains(&"-h".to_owned()) || args.contains(&"--help".to_owned());
+    // `pinput run CART` starts a runtime and connects only to that.
+    let launching = args.get(1).is_some_and(|command| command == "run");
 
     #[cfg(feature = "haptics")]
-    let haptics_server: Option<String> = {
-        let mut haptics_server = None;
-        for i in 1..args.len() - 1 {
-            if args[i] == "--haptics-server" {
-                haptics_server = Some(args[i + 1].clone());
-                break;
-            } else {
-                show_usage = true;
-                break;
+    let mut haptics_server: Option<String> = None;
+    let mut press_to_join = false;
+    let mut pid: Option<Pid> = None;
+    let mut gpio_address: Option<usize> = None;
+    let mut runtime_policy = RuntimePolicy::MirrorAll;
+    let mut scan_budget: Option<usize> = None;
+    let mut cart: Option<PathBuf> = None;
+    let mut runtime: Option<PathBuf> = None;
+    let mut flags = args.iter().skip(if launching { 2 } else { 1 });
+    // Flags with values need to pull from the iterator too.
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            #[cfg(feature = "haptics")]
+            "--haptics-server" => match flags.next() {
+                Some(address) => haptics_server = Some(address.clone()),
+                None => show_usage = true,
+            },
+            "--press-to-join" => press_to_join = true,
+            "--split-by-slot" => runtime_policy = RuntimePolicy::SplitBySlot,
+            "--pid" => match flags.next().and_then(|pid| pid.parse().ok()) {
+                Some(value) => pid = Some(value),
+                None => show_usage = true,
+            },
+            "--gpio-address" => match flags.next().and_then(|address| parse_address(address)) {
+                Some(address) => gpio_address = Some(address),
+                None => show_usage = true,
+            },
+            "--scan-budget" => match flags.next().and_then(|size| parse_size(size)) {
+                Some(size) => scan_budget = Some(size),
+                None => show_usage = true,
+            },
+            "--runtime" if launching => match flags.next() {
+                Some(path) => runtime = Some(PathBuf::from(path)),
+                None => show_usage = true,
+            },
+            _ if launching && cart.is_none() && !flag.starts_with('-') => {
+                cart = Some(PathBuf::from(flag))
             }
+            _ => show_usage = true,
         }
-        haptics_server
-    };
+    }
+
+    // A GPIO address means nothing without the process it's in.
+    show_usage = show_usage || (gpio_address.is_some() && pid.is_none());
+    // We know which process the runtime will be.
+    show_usage = show_usage || (launching && (cart.is_none() || pid.is_some()));
 
     if show_usage {
         #[cfg(feature = "haptics")]
-        println!("usage: {name} [--haptics-server ws://127.0.0.1:12345]");
+        let options =
+            "[--haptics-server ws://127.0.0.1:12345] [--press-to-join] [--scan-budget SIZE]";
         #[cfg(not(feature = "haptics"))]
-        println!("usage: {name}");
+        let options = "[--press-to-join] [--scan-budget SIZE]";
+        println!("usage: {name} {options} [--split-by-slot] [--pid PID [--gpio-address ADDRESS]]");
+        println!("       {name} run {options} [--runtime PATH] CART");
         return Ok(());
     }
 
-    check_prerequisites()?;
+    check_prerequisites(launching)?;
 
-    let keep_going = Arc::new(AtomicBool::new(true));
-    let keep_going_ctrlc = keep_going.clone();
-    ctrlc::set_handler(move || keep_going_ctrlc.store(false, Ordering::Relaxed))?;
+    let mut child = match &cart {
+        Some(cart) => {
+            let mut command = Command::from(runtime_command(cart, runtime.as_deref())?);
+            let program = command
+                .as_std()
+                .get_program()
+                .to_string_lossy()
+                .into_owned();
+            let child = command.spawn().map_err(|err| {
+                eprintln!("Failed to start {program}: {err}");
+                err
+            })?;
+            pid = child.id().map(|id| id as Pid);
+            if let Some(pid) = pid {
+                println!("Started {program} with PID {pid}");
+            }
+            Some(child)
+        }
+        None => None,
+    };
 
-    // Using the Windows raw input joystick driver breaks XInput devices on Windows.
-    // https://github.com/MysteriousJ/Joystick-Input-Examples#rawinput suggests that raw input
-    // needs a window to function, which we don't currently create.
-    sdl2::hint::set("SDL_JOYSTICK_RAWINPUT", "0");
+    let slot_file = default_slot_file();
+    if let Some(slot_file) = &slot_file {
+        println!("Remembering controller slots in {}", slot_file.display());
+    }
 
-    let sdl_context = sdl2::init().map_err(|s| Error::SdlStringError(s))?;
-    let joystick_subsystem = sdl_context
-        .joystick()
-        .map_err(|s| Error::SdlStringError(s))?;
-    let game_controller_subsystem = sdl_context
-        .game_controller()
-        .map_err(|s| Error::SdlStringError(s))?;
-    #[cfg(feature = "haptics")]
-    let haptic_subsystem = HapticSubsystem::new(haptics_server)?;
+    let runtime_file = default_runtime_file();
+    if let Some(runtime_file) = runtime_file.as_ref().filter(|path| path.exists()) {
+        println!("Loading extra runtimes from {}", runtime_file.display());
+    }
 
-    // TODO: treat `KilledByCtrlC` as a normal exit.
-    loop {
-        let runtime_connection = scan_for_runtime_connection(keep_going.clone())?;
+    // Our own child doesn't need the helper, and Yama may only let us in to it directly.
+    // A helper without the capability can't do anything we can't.
+    #[cfg(target_os = "linux")]
+    let memory_helper = helper_path().filter(|helper| !launching && has_ptrace_capability(helper));
+    #[cfg(target_os = "linux")]
+    if let Some(memory_helper) = &memory_helper {
+        println!(
+            "Accessing runtime memory through {}",
+            memory_helper.display()
+        );
+    }
+
+    let mut bridge = Bridge::new(BridgeOptions {
+        #[cfg(feature = "haptics")]
+        haptics_server,
+        slot_file,
+        press_to_join,
+        pid,
+        gpio_address,
+        runtime_policy,
+        runtime_file,
+        scan_budget,
+        #[cfg(target_os = "linux")]
+        memory_helper,
+    })
+    .await?;
+    bridge.on_connect(|runtime_connection| {
         println!(
             "connected: {}, PID {}",
             runtime_connection.flavor, runtime_connection.pid
-        );
-        run_gamepad_loop(
-            &keep_going,
-            &joystick_subsystem,
-            &game_controller_subsystem,
-            #[cfg(feature = "haptics")]
-            &haptic_subsystem,
-            &runtime_connection,
-        )?;
+        )
+    });
+    bridge.on_disconnect(|runtime_connection| {
+        println!(
+            "disconnected: {}, PID {}",
+            runtime_connection.flavor, runtime_connection.pid
+        )
+    });
+
+    bridge.on_cart_restart(|runtime_connection| {
+        println!(
+            "cart restarted: {}, PID {}",
+            runtime_connection.flavor, runtime_connection.pid
+        )
+    });
+
+    // Dropping the bridge's future on Ctrl-C stops it between frames.
+    let mut exit_status = None;
+    let result = tokio::select! {
+        result = bridge.run() => result,
+        result = tokio::signal::ctrl_c() => result.map_err(Error::from),
+        status = wait_for(&mut child) => status.map(|status| exit_status = Some(status)).map_err(Error::from),
+    };
+    bridge.shutdown();
+
+    // Don't leave a runtime we started running without us.
+    if let (Some(child), None) = (&mut child, exit_status) {
+        let _ = child.start_kill();
+        exit_status = child.wait().await.ok();
+    }
+    match exit_status {
+        Some(status) if result.is_ok() => process::exit(exit_code(status)),
+        _ => result,
+    }
+}
+
+/// Wait for the runtime we started to exit, or forever if we didn't start one.
+async fn wait_for(child: &mut Option<Child>) -> io::Result<ExitStatus> {
+    match child {
+        Some(child) => child.wait().await,
+        None => std::future::pending().await,
     }
 }
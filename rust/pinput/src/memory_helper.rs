@@ -0,0 +1,907 @@
+//! A small helper executable that holds `CAP_SYS_PTRACE` so that the rest of Pinput,
+//! including SDL, Buttplug, and their Bluetooth and network stacks, doesn't have to.
+//!
+//! The helper talks to Pinput over a socketpair passed as its standard input,
+//! and only does two things: find Pinput magic in a runtime process,
+//! and read or write the GPIO block it found there.
+//! It only touches processes that belong to the user running it and look like runtimes.
+
+use pinput_protocol::{PINPUT_GPIO_SIZE, PINPUT_MAX_GAMEPADS};
+use process_memory::Pid;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::os::fd::{AsFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use crate::gpio_backend::{handshake_spans, GpioBackend};
+use crate::magic_scanner::MagicScanner;
+use crate::runtime_connection::{
+    identify_runtime, is_no_such_process, Error, MemoryAccess, RuntimeConnection,
+};
+use crate::runtime_definition::RuntimeDefinition;
+
+/// File name of the helper executable, which lives next to `pinput`.
+pub const HELPER_NAME: &str = "pinput-helper";
+
+/// The helper executable next to the running one, if it's been installed.
+pub fn helper_path() -> Option<PathBuf> {
+    let path = std::env::current_exe().ok()?.with_file_name(HELPER_NAME);
+    path.exists().then_some(path)
+}
+
+/// Has `path` been granted `CAP_SYS_PTRACE`, as by `setcap cap_sys_ptrace+ep`?
+/// Copying or rebuilding the helper drops its file capabilities, so existing isn't enough.
+pub fn has_ptrace_capability(path: &Path) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // The largest revision of `vfs_cap_data` is 24 bytes.
+    let mut data = [0u8; 24];
+    // SAFETY: both names are NUL-terminated, and `data` is as long as we say it is.
+    let len = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            c"security.capability".as_ptr(),
+            data.as_mut_ptr().cast(),
+            data.len(),
+        )
+    };
+    usize::try_from(len).is_ok_and(|len| file_caps_grant_ptrace(&data[..len]))
+}
+
+/// Parse `vfs_cap_data` from capabilities(7): a little-endian word of revision and flags,
+/// then the low words of the permitted and inheritable sets, which is where `CAP_SYS_PTRACE` is.
+fn file_caps_grant_ptrace(data: &[u8]) -> bool {
+    const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x1;
+    let word = |index: usize| {
+        data.get(index * 4..index * 4 + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    match (word(0), word(1)) {
+        (Some(magic_etc), Some(permitted)) => {
+            magic_etc & VFS_CAP_FLAGS_EFFECTIVE != 0
+                && u64::from(permitted) & caps::Capability::CAP_SYS_PTRACE.bitmask() != 0
+        }
+        _ => false,
+    }
+}
+
+/// What Pinput can ask the helper to do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Request {
+    /// Search a runtime for Pinput magic, or check for it at `gpio_address` if given.
+    /// Later reads and writes for that PID go to wherever it was found.
+    Find {
+        pid: Pid,
+        gpio_address: Option<usize>,
+    },
+    Read {
+        pid: Pid,
+    },
+    /// Write these ranges of the GPIO block, each within one of the [`handshake_spans`].
+    Write {
+        pid: Pid,
+        gpio: [u8; PINPUT_GPIO_SIZE],
+        spans: Vec<Range<usize>>,
+    },
+}
+
+/// What went wrong in the helper, as far as Pinput needs to know.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FailureKind {
+    Other = 0,
+    PinputNotEnabled = 1,
+    AccessDenied = 2,
+    ProcessNotFound = 3,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Failure {
+    kind: FailureKind,
+    message: String,
+}
+
+/// The helper's answer to a [`Request`]: an address for `Find`, GPIO for `Read`, and nothing for `Write`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Reply {
+    Found(usize),
+    Gpio([u8; PINPUT_GPIO_SIZE]),
+    Written,
+}
+
+type Response = Result<Reply, Failure>;
+
+// Wire format: a tag byte, then little-endian fields, with no padding.
+
+const FIND: u8 = 0;
+const READ: u8 = 1;
+const WRITE: u8 = 2;
+
+const OK: u8 = 0;
+const FAILED: u8 = 1;
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0u8];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_gpio(reader: &mut impl Read) -> io::Result<[u8; PINPUT_GPIO_SIZE]> {
+    let mut gpio = [0u8; PINPUT_GPIO_SIZE];
+    reader.read_exact(&mut gpio)?;
+    Ok(gpio)
+}
+
+/// A range of the GPIO block, as a start and end byte.
+fn read_span(reader: &mut impl Read) -> io::Result<Range<usize>> {
+    let start = read_u8(reader)? as usize;
+    let end = read_u8(reader)? as usize;
+    // The helper never writes the cartridge's rumble bytes, apart from clearing magic,
+    // whatever it's asked.
+    if start > end || !handshake_spans().any(|span| span.start <= start && end <= span.end) {
+        return Err(invalid("span"));
+    }
+    Ok(start..end)
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad {what} from memory helper peer"),
+    )
+}
+
+impl Request {
+    fn pid(&self) -> Pid {
+        match *self {
+            Request::Find { pid, .. } | Request::Read { pid } | Request::Write { pid, .. } => pid,
+        }
+    }
+
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut message =
+            Vec::with_capacity(1 + 8 + 1 + 2 * PINPUT_MAX_GAMEPADS + PINPUT_GPIO_SIZE);
+        let tag = match self {
+            Request::Find { .. } => FIND,
+            Request::Read { .. } => READ,
+            Request::Write { .. } => WRITE,
+        };
+        message.push(tag);
+        message.extend((self.pid() as i64).to_le_bytes());
+        match self {
+            Request::Find { gpio_address, .. } => {
+                message.push(gpio_address.is_some() as u8);
+                message.extend((gpio_address.unwrap_or_default() as u64).to_le_bytes());
+            }
+            Request::Read { .. } => {}
+            Request::Write { gpio, spans, .. } => {
+                if spans.len() > PINPUT_MAX_GAMEPADS {
+                    return Err(invalid("span count"));
+                }
+                message.push(spans.len() as u8);
+                for span in spans {
+                    message.extend([span.start as u8, span.end as u8]);
+                }
+                message.extend(gpio);
+            }
+        }
+        writer.write_all(&message)
+    }
+
+    /// `None` if the other end has hung up.
+    fn read_from(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        let tag = match read_u8(reader) {
+            Ok(tag) => tag,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let pid = Pid::try_from(read_u64(reader)? as i64).map_err(|_| invalid("PID"))?;
+        let request = match tag {
+            FIND => {
+                let has_address = read_u8(reader)? != 0;
+                let address = read_u64(reader)? as usize;
+                Request::Find {
+                    pid,
+                    gpio_address: has_address.then_some(address),
+                }
+            }
+            READ => Request::Read { pid },
+            WRITE => {
+                let count = read_u8(reader)? as usize;
+                if count > PINPUT_MAX_GAMEPADS {
+                    return Err(invalid("span count"));
+                }
+                let spans = (0..count)
+                    .map(|_| read_span(reader))
+                    .collect::<io::Result<_>>()?;
+                Request::Write {
+                    pid,
+                    gpio: read_gpio(reader)?,
+                    spans,
+                }
+            }
+            _ => return Err(invalid("request")),
+        };
+        Ok(Some(request))
+    }
+}
+
+fn write_response(writer: &mut impl Write, response: &Response) -> io::Result<()> {
+    let mut message = Vec::new();
+    match response {
+        Ok(reply) => {
+            message.push(OK);
+            match reply {
+                Reply::Found(address) => message.extend((*address as u64).to_le_bytes()),
+                Reply::Gpio(gpio) => message.extend(gpio),
+                Reply::Written => {}
+            }
+        }
+        Err(failure) => {
+            message.push(FAILED);
+            message.push(failure.kind as u8);
+            message.extend((failure.message.len() as u64).to_le_bytes());
+            message.extend(failure.message.as_bytes());
+        }
+    }
+    writer.write_all(&message)
+}
+
+/// Responses don't say what they're for, so this needs to know what was asked.
+fn read_response(reader: &mut impl Read, request: &Request) -> io::Result<Response> {
+    match read_u8(reader)? {
+        OK => Ok(Ok(match request {
+            Request::Find { .. } => Reply::Found(read_u64(reader)? as usize),
+            Request::Read { .. } => Reply::Gpio(read_gpio(reader)?),
+            Request::Write { .. } => Reply::Written,
+        })),
+        FAILED => {
+            let kind = match read_u8(reader)? {
+                1 => FailureKind::PinputNotEnabled,
+                2 => FailureKind::AccessDenied,
+                3 => FailureKind::ProcessNotFound,
+                _ => FailureKind::Other,
+            };
+            let len = usize::try_from(read_u64(reader)?).map_err(|_| invalid("message"))?;
+            let mut message = vec![0u8; len];
+            reader.read_exact(&mut message)?;
+            Ok(Err(Failure {
+                kind,
+                message: String::from_utf8_lossy(&message).into_owned(),
+            }))
+        }
+        _ => Err(invalid("response")),
+    }
+}
+
+/// Failures Pinput has a variant for only carry the OS error behind them, if there is one,
+/// since Pinput knows the rest already.
+impl From<Error> for Failure {
+    fn from(err: Error) -> Self {
+        let kind = match err {
+            Error::PinputNotEnabled { .. } | Error::PinputNotEnabledAt { .. } => {
+                FailureKind::PinputNotEnabled
+            }
+            Error::AccessDenied { .. } => FailureKind::AccessDenied,
+            Error::ProcessNotFound { .. } => FailureKind::ProcessNotFound,
+            _ => FailureKind::Other,
+        };
+        let message = match kind {
+            FailureKind::Other => err.to_string(),
+            _ => std::error::Error::source(&err)
+                .map(ToString::to_string)
+                .unwrap_or_default(),
+        };
+        Failure { kind, message }
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        let kind = if err.kind() == io::ErrorKind::PermissionDenied {
+            FailureKind::AccessDenied
+        } else if is_no_such_process(&err) {
+            FailureKind::ProcessNotFound
+        } else {
+            FailureKind::Other
+        };
+        Failure {
+            kind,
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<Failure> for io::Error {
+    fn from(failure: Failure) -> Self {
+        let kind = match failure.kind {
+            FailureKind::AccessDenied => io::ErrorKind::PermissionDenied,
+            FailureKind::ProcessNotFound => io::ErrorKind::NotFound,
+            FailureKind::PinputNotEnabled | FailureKind::Other => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, failure.message)
+    }
+}
+
+/// Pinput's ends of the socketpairs, shared by every runtime connection that goes through the helper.
+/// Searches can take a while, so they go to a helper process of their own,
+/// and never hold up reads and writes for runtimes that are already connected.
+#[derive(Debug)]
+pub struct HelperClient {
+    /// Reads and writes, and checks of GPIO addresses that have already been found.
+    stream: Mutex<UnixStream>,
+    /// Searches for GPIO blocks.
+    search_stream: Mutex<UnixStream>,
+    /// Empty when we didn't start the helpers ourselves, as in tests.
+    children: Vec<Child>,
+}
+
+impl HelperClient {
+    /// Start the helper at `path`, which reads at most `scan_budget` bytes of each runtime per search.
+    pub fn spawn(path: &Path, scan_budget: usize) -> io::Result<Self> {
+        let spawn = || -> io::Result<(UnixStream, Child)> {
+            let (ours, theirs) = UnixStream::pair()?;
+            let child = Command::new(path)
+                .arg("--scan-budget")
+                .arg(scan_budget.to_string())
+                .stdin(Stdio::from(OwnedFd::from(theirs)))
+                .spawn()?;
+            Ok((ours, child))
+        };
+        let (stream, child) = spawn()?;
+        let (search_stream, search_child) = spawn()?;
+        Ok(HelperClient {
+            stream: Mutex::new(stream),
+            search_stream: Mutex::new(search_stream),
+            children: vec![child, search_child],
+        })
+    }
+
+    /// PIDs of the helper processes we started.
+    pub fn pids(&self) -> Vec<Pid> {
+        self.children
+            .iter()
+            .map(|child| child.id() as Pid)
+            .collect()
+    }
+
+    fn call(&self, request: &Request) -> io::Result<Response> {
+        Self::call_on(&self.stream, request)
+    }
+
+    fn call_on(stream: &Mutex<UnixStream>, request: &Request) -> io::Result<Response> {
+        let mut stream = stream.lock().unwrap_or_else(|err| err.into_inner());
+        request.write_to(&mut *stream)?;
+        read_response(&mut *stream, request)
+    }
+
+    /// Ask the helper to find the GPIO block of the runtime with `pid`, running `exe`,
+    /// or to check the one at `gpio_address`. Either way, the helper then reads and writes it
+    /// for [`HelperGpio`].
+    pub fn find(&self, pid: Pid, gpio_address: Option<usize>, exe: &Path) -> Result<usize, Error> {
+        let gpio_address = match gpio_address {
+            Some(address) => address,
+            None => self.request_find(&self.search_stream, pid, None, exe)?,
+        };
+        self.request_find(&self.stream, pid, Some(gpio_address), exe)
+    }
+
+    fn request_find(
+        &self,
+        stream: &Mutex<UnixStream>,
+        pid: Pid,
+        gpio_address: Option<usize>,
+        exe: &Path,
+    ) -> Result<usize, Error> {
+        let response = Self::call_on(stream, &Request::Find { pid, gpio_address })?;
+        let failure = match response {
+            Ok(Reply::Found(address)) => return Ok(address),
+            Ok(_) => return Err(invalid("reply").into()),
+            Err(failure) => failure,
+        };
+        let path = exe.to_path_buf();
+        let kind = failure.kind;
+        let source = (!failure.message.is_empty()).then(|| io::Error::from(failure.clone()));
+        Err(match (kind, gpio_address) {
+            (FailureKind::PinputNotEnabled, Some(address)) => {
+                Error::PinputNotEnabledAt { pid, path, address }
+            }
+            (FailureKind::PinputNotEnabled, None) => Error::PinputNotEnabled { pid, path, source },
+            (FailureKind::AccessDenied, _) => Error::AccessDenied {
+                pid,
+                path,
+                source: failure.into(),
+            },
+            (FailureKind::ProcessNotFound, _) => Error::ProcessNotFound {
+                pid,
+                path: Some(path),
+                source,
+            },
+            (FailureKind::Other, _) => Error::Helper(failure.message),
+        })
+    }
+}
+
+impl Drop for HelperClient {
+    fn drop(&mut self) {
+        // The helpers exit when they see their ends of the sockets close.
+        for stream in [&mut self.stream, &mut self.search_stream] {
+            let _ = stream
+                .get_mut()
+                .map(|stream| stream.shutdown(std::net::Shutdown::Both));
+        }
+        for child in &mut self.children {
+            let _ = child.wait();
+        }
+    }
+}
+
+/// A runtime's GPIO block, read and written by the helper.
+#[derive(Debug)]
+pub struct HelperGpio {
+    helper: Arc<HelperClient>,
+    pid: Pid,
+}
+
+impl HelperGpio {
+    pub fn new(helper: Arc<HelperClient>, pid: Pid) -> Self {
+        HelperGpio { helper, pid }
+    }
+}
+
+impl GpioBackend for HelperGpio {
+    fn has_magic(&mut self) -> io::Result<bool> {
+        Ok(pinput_protocol::has_magic(&self.read()?))
+    }
+
+    fn read(&mut self) -> io::Result<[u8; PINPUT_GPIO_SIZE]> {
+        match self.helper.call(&Request::Read { pid: self.pid })? {
+            Ok(Reply::Gpio(gpio)) => Ok(gpio),
+            Ok(_) => Err(invalid("reply")),
+            Err(failure) => Err(failure.into()),
+        }
+    }
+
+    fn write_spans(
+        &mut self,
+        gpio: &[u8; PINPUT_GPIO_SIZE],
+        spans: &[Range<usize>],
+    ) -> io::Result<()> {
+        if spans.is_empty() {
+            // Nothing changed, so don't bother the helper.
+            return Ok(());
+        }
+        let request = Request::Write {
+            pid: self.pid,
+            gpio: *gpio,
+            spans: spans.to_vec(),
+        };
+        match self.helper.call(&request)? {
+            Ok(Reply::Written) => Ok(()),
+            Ok(_) => Err(invalid("reply")),
+            Err(failure) => Err(failure.into()),
+        }
+    }
+
+    /// The helper has already followed the GPIO block when it found it again.
+    fn relocate(&mut self, _gpio_address: usize) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Real and effective user IDs of a process, from `/proc/<pid>/status`.
+fn user_ids(pid: &str) -> io::Result<(u32, u32)> {
+    let status = fs::read_to_string(format!("/proc/{pid}/status"))?;
+    let ids: Vec<u32> = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))
+        .map(|ids| {
+            ids.split_whitespace()
+                .filter_map(|id| id.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    match ids[..] {
+        [real, effective, ..] => Ok((real, effective)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "no Uid line")),
+    }
+}
+
+/// Only help with runtimes that the user who started the helper could already debug
+/// if it weren't for Yama. The helper's own effective user ID doesn't change with capabilities.
+fn check_owner(pid: Pid) -> Result<(), Failure> {
+    let (user, _) = user_ids("self")?;
+    match user_ids(&pid.to_string()) {
+        Ok((real, effective)) if real == user && effective == user => Ok(()),
+        Ok(_) => Err(Failure {
+            kind: FailureKind::AccessDenied,
+            message: format!("PID {pid} belongs to another user"),
+        }),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The helper's side: connections it's found, and searches still in progress.
+struct HelperState {
+    definitions: Vec<Arc<RuntimeDefinition>>,
+    scan_budget: usize,
+    connections: HashMap<Pid, RuntimeConnection>,
+    searches: HashMap<Pid, MemoryAccess>,
+}
+
+impl HelperState {
+    fn handle(&mut self, request: Request) -> Response {
+        let pid = request.pid();
+        let response = self.respond(request);
+        // Forget runtimes that have quit, so that nothing reaches a process that reuses the PID
+        // until Pinput has found its GPIO block all over again.
+        if matches!(&response, Err(failure) if failure.kind == FailureKind::ProcessNotFound) {
+            self.connections.remove(&pid);
+            self.searches.remove(&pid);
+        }
+        response
+    }
+
+    fn respond(&mut self, request: Request) -> Response {
+        match request {
+            Request::Find { pid, gpio_address } => {
+                // Whatever we found before, Pinput doesn't trust it anymore.
+                self.connections.remove(&pid);
+                // Searches of runtimes that quit while we were searching them.
+                self.searches
+                    .retain(|&pid, _| Path::new(&format!("/proc/{pid}")).exists());
+                check_owner(pid)?;
+                if identify_runtime(&self.definitions, pid)?.is_none() {
+                    return Err(Failure {
+                        kind: FailureKind::Other,
+                        message: format!(
+                            "PID {pid} isn't a runtime the helper knows; \
+                            describe it in {SYSTEM_RUNTIME_FILE} for the helper to search it"
+                        ),
+                    });
+                }
+                let scan_budget = self.scan_budget;
+                let access = self
+                    .searches
+                    .entry(pid)
+                    .or_insert_with(|| MemoryAccess::Direct(MagicScanner::new(scan_budget)));
+                let connection =
+                    RuntimeConnection::try_attach(&self.definitions, pid, gpio_address, access)?;
+                self.searches.remove(&pid);
+                let address = connection
+                    .gpio_address
+                    .expect("connections to other processes have addresses");
+                self.connections.insert(pid, connection);
+                Ok(Reply::Found(address))
+            }
+            Request::Read { pid } => Ok(Reply::Gpio(self.connection(pid)?.gpio.read()?)),
+            Request::Write { pid, gpio, spans } => {
+                self.connection(pid)?.gpio.write_spans(&gpio, &spans)?;
+                Ok(Reply::Written)
+            }
+        }
+    }
+
+    fn connection(&mut self, pid: Pid) -> Result<&mut RuntimeConnection, Failure> {
+        self.connections.get_mut(&pid).ok_or_else(|| Failure {
+            kind: FailureKind::Other,
+            message: format!("no GPIO block found in PID {pid} yet"),
+        })
+    }
+}
+
+/// Answer requests until the other end hangs up.
+fn serve_with(
+    stream: &mut UnixStream,
+    mut handle: impl FnMut(Request) -> Response,
+) -> io::Result<()> {
+    while let Some(request) = Request::read_from(stream)? {
+        write_response(stream, &handle(request))?;
+    }
+    Ok(())
+}
+
+/// Only root can tell the helper about runtimes it doesn't know,
+/// since it lets whoever runs it into any process that looks like one.
+pub const SYSTEM_RUNTIME_FILE: &str = "/etc/pinput/runtimes.json";
+
+/// Can only root have written `path`? Anything less, and any user could make the helper
+/// treat their other processes as runtimes.
+fn is_root_owned(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.uid() == 0 && metadata.mode() & 0o022 == 0)
+}
+
+/// Run the helper on the socket Pinput passed as our standard input.
+/// Knows the built-in runtimes, and those in [`SYSTEM_RUNTIME_FILE`] if root owns it,
+/// but never takes runtime definitions from whoever started it.
+pub fn serve(scan_budget: usize) -> io::Result<()> {
+    let system_runtime_file = Path::new(SYSTEM_RUNTIME_FILE);
+    let runtime_file = if is_root_owned(system_runtime_file) {
+        Some(system_runtime_file)
+    } else {
+        if system_runtime_file.exists() {
+            println!("Ignoring {SYSTEM_RUNTIME_FILE}: only root should be able to change it");
+        }
+        None
+    };
+    let mut stream = UnixStream::from(io::stdin().as_fd().try_clone_to_owned()?);
+    let mut state = HelperState {
+        definitions: crate::runtime_definition::load_runtime_definitions(runtime_file),
+        scan_budget,
+        connections: HashMap::new(),
+        searches: HashMap::new(),
+    };
+    serve_with(&mut stream, |request| state.handle(request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pinput_protocol::PINPUT_MAGIC;
+    use std::thread;
+
+    #[test]
+    fn requests_round_trip() {
+        let requests = [
+            Request::Find {
+                pid: 18649,
+                gpio_address: None,
+            },
+            Request::Find {
+                pid: 18649,
+                gpio_address: Some(0x7f00_1234_5f80),
+            },
+            Request::Read { pid: 1 },
+            Request::Write {
+                pid: 2,
+                gpio: [0xa5; PINPUT_GPIO_SIZE],
+                spans: vec![0..14, 48..62],
+            },
+            Request::Write {
+                pid: 2,
+                gpio: [0x5a; PINPUT_GPIO_SIZE],
+                spans: handshake_spans().collect(),
+            },
+        ];
+        let mut wire = Vec::new();
+        for request in &requests {
+            request.write_to(&mut wire).unwrap();
+        }
+        let mut reader = &wire[..];
+        for request in &requests {
+            assert_eq!(
+                Request::read_from(&mut reader).unwrap().as_ref(),
+                Some(request)
+            );
+        }
+        assert_eq!(Request::read_from(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn file_caps_are_parsed() {
+        // What `setcap cap_sys_ptrace+ep` writes: revision 2, effective, permitted bit 19.
+        let ptrace_ep = [
+            0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        assert!(file_caps_grant_ptrace(&ptrace_ep));
+        // `setcap cap_sys_ptrace+p` doesn't make it effective.
+        let mut ptrace_p = ptrace_ep;
+        ptrace_p[0] = 0x00;
+        assert!(!file_caps_grant_ptrace(&ptrace_p));
+        // Some other capability.
+        let mut other_ep = ptrace_ep;
+        other_ep[6] = 0x04;
+        assert!(!file_caps_grant_ptrace(&other_ep));
+        assert!(!file_caps_grant_ptrace(&[]));
+        assert!(!has_ptrace_capability(Path::new(
+            "/nonexistent/pinput-helper"
+        )));
+    }
+
+    #[test]
+    fn writes_to_rumble_bytes_are_refused() {
+        let request = Request::Write {
+            pid: 2,
+            gpio: [0; PINPUT_GPIO_SIZE],
+            spans: vec![16..30, 30..32],
+        };
+        let mut wire = Vec::new();
+        request.write_to(&mut wire).unwrap();
+        let err = Request::read_from(&mut &wire[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// A helper that pretends PID 1 has GPIO at 0x5f80, PID 3 has quit, and refuses anything else.
+    /// Only the search helper searches, and only the other one reads and writes.
+    fn fake_helper() -> Arc<HelperClient> {
+        let serve = |searching: bool| {
+            let (ours, mut theirs) = UnixStream::pair().unwrap();
+            thread::spawn(move || {
+                let mut gpio = [0u8; PINPUT_GPIO_SIZE];
+                gpio[..PINPUT_MAGIC.len()].copy_from_slice(&PINPUT_MAGIC);
+                serve_with(&mut theirs, |request| match request {
+                    Request::Find {
+                        pid: 1,
+                        gpio_address,
+                    } if gpio_address.is_none() == searching => Ok(Reply::Found(0x5f80)),
+                    Request::Read { pid: 1 } if !searching => Ok(Reply::Gpio(gpio)),
+                    Request::Write {
+                        pid: 1, gpio: new, ..
+                    } if !searching => {
+                        gpio = new;
+                        Ok(Reply::Written)
+                    }
+                    Request::Find { pid: 3, .. } => Err(Failure {
+                        kind: FailureKind::ProcessNotFound,
+                        message: "No such process".to_owned(),
+                    }),
+                    _ => Err(Failure {
+                        kind: FailureKind::AccessDenied,
+                        message: "Operation not permitted".to_owned(),
+                    }),
+                })
+            });
+            Mutex::new(ours)
+        };
+        Arc::new(HelperClient {
+            stream: serve(false),
+            search_stream: serve(true),
+            children: vec![],
+        })
+    }
+
+    #[test]
+    fn gpio_goes_through_the_helper() {
+        let helper = fake_helper();
+        let exe = Path::new("/usr/bin/pico8");
+        assert_eq!(helper.find(1, None, exe).unwrap(), 0x5f80);
+
+        let mut gpio = HelperGpio::new(helper, 1);
+        assert!(gpio.has_magic().unwrap());
+        assert_eq!(gpio.read().unwrap()[..PINPUT_MAGIC.len()], PINPUT_MAGIC);
+        gpio.write(&[0; PINPUT_GPIO_SIZE]).unwrap();
+        assert!(!gpio.has_magic().unwrap());
+    }
+
+    #[test]
+    fn searches_dont_hold_up_reads() {
+        let fake = fake_helper();
+        // A search helper that never answers.
+        let (search_stream, stuck) = UnixStream::pair().unwrap();
+        let helper = Arc::new(HelperClient {
+            stream: Mutex::new(fake.stream.lock().unwrap().try_clone().unwrap()),
+            search_stream: Mutex::new(search_stream),
+            children: vec![],
+        });
+
+        let search = {
+            let helper = helper.clone();
+            thread::spawn(move || helper.find(1, None, Path::new("/usr/bin/pico8")))
+        };
+        let mut gpio = HelperGpio::new(helper, 1);
+        assert!(gpio.has_magic().unwrap());
+
+        drop(stuck);
+        assert!(search.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn helper_failures_become_errors() {
+        let helper = fake_helper();
+        let exe = Path::new("/usr/bin/pico8");
+        assert!(matches!(
+            helper.find(2, None, exe),
+            Err(Error::AccessDenied { pid: 2, .. })
+        ));
+        let gone = helper.find(3, None, exe).unwrap_err();
+        assert!(matches!(
+            gone,
+            Error::ProcessNotFound {
+                pid: 3,
+                path: Some(_),
+                source: Some(_)
+            }
+        ));
+        assert_eq!(
+            gone.to_string(),
+            "no process with PID 3 (/usr/bin/pico8): No such process"
+        );
+
+        let mut gpio = HelperGpio::new(helper, 2);
+        assert_eq!(
+            gpio.read().unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+
+    fn helper_state() -> HelperState {
+        HelperState {
+            definitions: crate::runtime_definition::load_runtime_definitions(None),
+            scan_budget: 0,
+            connections: HashMap::new(),
+            searches: HashMap::new(),
+        }
+    }
+
+    fn connection(pid: Pid, gpio: impl GpioBackend + 'static) -> RuntimeConnection {
+        let flavor = Arc::new(RuntimeDefinition::unknown());
+        RuntimeConnection::with_backend(pid, flavor, PathBuf::from("/usr/bin/pico8"), gpio)
+    }
+
+    #[test]
+    fn exited_runtimes_are_forgotten() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let pid = child.id() as Pid;
+        let gpio = crate::gpio_backend::ProcMemGpio::open(pid, 0x1000).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        let mut state = helper_state();
+        state.connections.insert(pid, connection(pid, gpio));
+        let failure = state.handle(Request::Read { pid }).unwrap_err();
+        assert_eq!(failure.kind, FailureKind::ProcessNotFound);
+        assert!(state.connections.is_empty());
+    }
+
+    #[test]
+    fn finding_again_drops_the_old_connection() {
+        // We aren't a runtime, so the search fails, but the old connection still goes.
+        let pid = std::process::id() as Pid;
+        let mut state = helper_state();
+        state
+            .connections
+            .insert(pid, connection(pid, [0u8; PINPUT_GPIO_SIZE]));
+        let request = Request::Find {
+            pid,
+            gpio_address: None,
+        };
+        assert!(state.handle(request).is_err());
+        assert!(state.connections.is_empty());
+        let failure = state.handle(Request::Read { pid }).unwrap_err();
+        assert!(failure.message.contains("no GPIO block found"));
+    }
+
+    #[test]
+    fn only_root_owned_runtime_files_are_trusted() {
+        let path = std::env::temp_dir().join(format!(
+            "pinput-helper-runtimes-{}.json",
+            std::process::id()
+        ));
+        fs::write(&path, "{}").unwrap();
+        let set_mode = |mode| {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        };
+        set_mode(0o666);
+        let world_writable = is_root_owned(&path);
+        set_mode(0o644);
+        let ours = is_root_owned(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(!world_writable);
+        let (user, _) = user_ids("self").unwrap();
+        assert_eq!(ours, user == 0);
+        assert!(!is_root_owned(Path::new("/nonexistent/runtimes.json")));
+    }
+
+    #[test]
+    fn user_ids_are_parsed() {
+        let (real, effective) = user_ids("self").unwrap();
+        assert_eq!(real, effective);
+    }
+}
@@ -1,30 +1,93 @@
-use sysinfo;
-use proc_maps;
-use sysinfo::{System, SystemExt, PidExt, Process, ProcessExt, ProcessRefreshKind, RefreshKind};
+use pinput_protocol::{PINPUT_GPIO_SIZE, PINPUT_MAGIC};
+use process_memory::{Pid, ProcessHandle, TryIntoProcessHandle};
+use std::io;
 use std::path::{Path, PathBuf};
-use plist;
-use std::ffi::OsStr;
-use proc_maps::MapRange;
-use serde::Deserialize;
-use process_memory;
-use process_memory::{DataMember, Pid, ProcessHandle, TryIntoProcessHandle};
-use memchr::memmem;
-use uuid::Uuid;
-use std::fmt::{Display, Formatter};
-
-use super::constants::PINPUT_MAGIC;
-use super::gamepad::PinputGamepadArray;
+use std::sync::Arc;
+use sysinfo::{PidExt, Process, ProcessExt, ProcessRefreshKind, RefreshKind, System, SystemExt};
+
+#[cfg(not(target_os = "macos"))]
+use crate::exe_inspector;
+#[cfg(target_os = "linux")]
+use crate::gpio_backend::ProcMemGpio;
+use crate::gpio_backend::{GpioBackend, ProcessMemoryGpio};
+use crate::magic_scanner::{MagicScanner, Region};
+#[cfg(target_os = "linux")]
+use crate::memory_helper::{helper_path, HelperClient, HelperGpio};
+use crate::runtime_definition::{program_path, RuntimeDefinition};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("no running PICO-8 or WASM-4 processes found")]
+    #[error("no running runtime processes found")]
     NoProcessesFound,
 
-    #[error("Pinput magic bytes not found in process (PID {0})")]
-    PinputNotEnabled(Pid),
+    /// The runtime has quit, or there never was such a process.
+    #[error("no process with PID {pid}{}{}", in_parens(.path), after_colon(.source))]
+    ProcessNotFound {
+        pid: Pid,
+        /// What it was running, if we saw it before it went.
+        path: Option<PathBuf>,
+        #[source]
+        source: Option<io::Error>,
+    },
+
+    /// The OS won't let us into the runtime's memory. Retrying won't help until the user fixes that.
+    #[error(
+        "not allowed to access memory of PID {pid} ({}): {source}\n{}",
+        .path.display(),
+        access_remedy()
+    )]
+    AccessDenied {
+        pid: Pid,
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The runtime is running, but its cartridge hasn't written Pinput magic yet,
+    /// or it's in memory we couldn't read, as `source` says.
+    #[error(
+        "Pinput magic bytes not found in PID {pid} ({}){}",
+        .path.display(),
+        unreadable(.source)
+    )]
+    PinputNotEnabled {
+        pid: Pid,
+        path: PathBuf,
+        #[source]
+        source: Option<io::Error>,
+    },
+
+    #[error(
+        "Pinput magic bytes not found at GPIO address {address:#x} in PID {pid} ({})",
+        .path.display()
+    )]
+    PinputNotEnabledAt {
+        pid: Pid,
+        path: PathBuf,
+        address: usize,
+    },
+
+    #[error(
+        "GPIO address {address:#x} isn't in a readable and writable memory region of PID {pid}"
+    )]
+    GpioAddressNotMapped { pid: Pid, address: usize },
+
+    /// Memory we were using was unmapped, usually because the runtime freed it or quit.
+    #[error(
+        "memory at {address:#x} in PID {pid} ({}) went away: {source}",
+        .path.display()
+    )]
+    RegionVanished {
+        pid: Pid,
+        path: PathBuf,
+        address: usize,
+        #[source]
+        source: io::Error,
+    },
 
-    #[error("Pinput magic bytes not found in any process memory region")]
-    PinputMagicNotFound,
+    /// Something went wrong in the memory helper that we don't have a better variant for.
+    #[error("memory helper: {0}")]
+    Helper(String),
 
     #[error("couldn't find app bundle containing {path}")]
     AppBundle { path: PathBuf },
@@ -33,240 +96,609 @@ pub enum Error {
     Plist(#[from] plist::Error),
 
     #[error("I/O error")]
-    IOError(#[from] std::io::Error),
+    IOError(#[from] io::Error),
 }
 
-/// File name of the regular PICO-8 executable (not the one from a standalone cartridge).
-#[cfg(windows)]
-static PICO8_EXECUTABLE_NAME: &str = "pico8.exe";
-
-/// File name of the regular PICO-8 executable (not the one from a standalone cartridge).
-#[cfg(not(windows))]
-static PICO8_EXECUTABLE_NAME: &str = "pico8";
-
-/// Subset of `Info.plist` for a macOS app.
-/// See <https://developer.apple.com/library/archive/documentation/CoreFoundation/Conceptual/CFBundles/BundleTypes/BundleTypes.html#//apple_ref/doc/uid/10000123i-CH101-SW19>.
-#[derive(Deserialize)]
-#[serde(rename_all = "PascalCase")]
-struct InfoPlist {
-    /// Name of the app's main executable file.
-    c_f_bundle_executable: String,
-    /// Bundle ID, used for identifying a specific app to various system APIs, and to Pinput.
-    c_f_bundle_identifier: String,
+impl Error {
+    /// Sort out why reading or writing a runtime's memory at `address` failed,
+    /// or why we couldn't look at the runtime at all if there's no address.
+    fn from_access(pid: Pid, path: &Path, address: Option<usize>, err: io::Error) -> Self {
+        if is_no_such_process(&err) {
+            return Error::ProcessNotFound {
+                pid,
+                path: Some(path.to_path_buf()),
+                source: Some(err),
+            };
+        }
+        match (err.kind(), address) {
+            (io::ErrorKind::PermissionDenied, _) => Error::AccessDenied {
+                pid,
+                path: path.to_path_buf(),
+                source: err,
+            },
+            (_, Some(address)) => Error::RegionVanished {
+                pid,
+                path: path.to_path_buf(),
+                address,
+                source: err,
+            },
+            (_, None) => Error::IOError(err),
+        }
+    }
 }
 
-/// If this file is inside a macOS app bundle, return the path to that bundle.
-fn find_app_bundle_path(path: &Path) -> Result<&Path, Error> {
-    let app_extension = Some(OsStr::new("app"));
-    path.ancestors()
-        .find(|path| path.extension() == app_extension)
-        .ok_or_else(|| Error::AppBundle { path: PathBuf::from(path) })
+/// ` (path)`, if there is one.
+fn in_parens(path: &Option<PathBuf>) -> String {
+    path.as_ref()
+        .map(|path| format!(" ({})", path.display()))
+        .unwrap_or_default()
 }
 
-/// Assume the path is for a macOS app bundle.
-/// Load the `Info.plist` for that bundle.
-fn load_info_plist(app_bundle_path: &Path) -> Result<InfoPlist, plist::Error> {
-    let info_plist_path = app_bundle_path.join("Contents/Info.plist");
-    plist::from_file(info_plist_path)
+/// `: error`, if there is one.
+fn after_colon(source: &Option<io::Error>) -> String {
+    source
+        .as_ref()
+        .map(|err| format!(": {err}"))
+        .unwrap_or_default()
 }
 
-// TODO: run this check only on macOS
-fn is_main_executable_of_pico8_bundle(path: &Path) -> Result<bool, Error> {
-    let app_bundle_path = find_app_bundle_path(path)?;
-    let info_plist = load_info_plist(app_bundle_path)?;
-
-    let in_pico8_bundle = info_plist.c_f_bundle_identifier == "com.lexaloffle.pico8"
-        || info_plist.c_f_bundle_identifier.starts_with("com.pico8_author.");
+/// Why some of a runtime's memory couldn't be searched, if there was any.
+fn unreadable(source: &Option<io::Error>) -> String {
+    source
+        .as_ref()
+        .map(|err| format!(", and some of its memory couldn't be read: {err}"))
+        .unwrap_or_default()
+}
 
-    let bundle_main_executable_path =
-        app_bundle_path
-        .join("Contents/MacOS")
-        .join(info_plist.c_f_bundle_executable);
-    let is_bundle_main_executable = path == bundle_main_executable_path;
+/// The process is gone. Listing mappings reports this as a missing `/proc/<pid>` file on Linux,
+/// and reading memory reports it as `ESRCH`.
+pub fn is_no_such_process(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    if err.raw_os_error() == Some(libc::ESRCH) {
+        return true;
+    }
+    err.kind() == io::ErrorKind::NotFound
+}
 
-    Ok(in_pico8_bundle && is_bundle_main_executable)
+/// Who may read whose memory under the Yama security module, if it's enabled:
+/// https://www.kernel.org/doc/html/latest/admin-guide/LSM/Yama.html
+/// 0 is anyone running as the same user, 1 is only their own descendants,
+/// 2 is only with `CAP_SYS_PTRACE`, and 3 is nobody.
+#[cfg(target_os = "linux")]
+pub fn yama_ptrace_scope() -> Option<u8> {
+    std::fs::read_to_string("/proc/sys/kernel/yama/ptrace_scope")
+        .ok()
+        .and_then(|scope| scope.trim().parse().ok())
 }
 
-/// Detect both PICO-8 and standalone cartridges.
-fn is_pico8_exe(path: &Path) -> Result<bool, Error> {
-    if path.ends_with(PICO8_EXECUTABLE_NAME) {
-        Ok(true)
-    } else if is_main_executable_of_pico8_bundle(path)? {
-        Ok(true)
-    } else {
-        // PICO-8 on Windows doesn't use the PE `VERSIONINFO` resource that is the closest
-        // equivalent of `Info.plist`, either in the regular PICO-8 binary or standalone cartridges,
-        // so we can't detect PICO-8 as easily on Windows.
-        // Linux doesn't have *any* kind of convenient executable metadata.
-        // TODO: parse candidate executable files, look for symbols like `_p8_*`.
-        Ok(false)
+/// What to do about [`Error::AccessDenied`].
+#[cfg(target_os = "linux")]
+fn access_remedy() -> String {
+    if yama_ptrace_scope() == Some(3) {
+        return "Yama's ptrace_scope is 3, which stops any process reading another's memory. \
+                Set the kernel.yama.ptrace_scope sysctl to 2 or lower, and reboot."
+            .to_owned();
     }
+    // With the helper installed, it's the one that needs the capability.
+    let exe = helper_path()
+        .or_else(|| std::env::current_exe().ok())
+        .map(|exe| exe.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "pinput".to_owned());
+    format!(
+        "Make sure the runtime is running as the same user as Pinput, \
+         and that {exe} has the CAP_SYS_PTRACE capability: sudo setcap cap_sys_ptrace+ep {exe}"
+    )
 }
 
-fn is_pico8_process(process: &Process) -> Result<bool, Error> {
-    is_pico8_exe(process.exe())
+/// What to do about [`Error::AccessDenied`].
+#[cfg(target_os = "macos")]
+fn access_remedy() -> String {
+    "Run Pinput as root, with sudo.".to_owned()
 }
 
-#[cfg(not(target_os = "linux"))]
-fn is_pico8_data_segment(map: &MapRange) -> Result<bool, Error> {
-    let map_permissions_rw_only = map.is_read() && map.is_write() && !map.is_exec();
-    let map_is_from_pico8_executable = if let Some(path) = map.filename() {
-        is_pico8_exe(Path::new(&path))?
-    } else {
-        false
-    };
-    Ok(map_permissions_rw_only && map_is_from_pico8_executable)
+/// What to do about [`Error::AccessDenied`].
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn access_remedy() -> String {
+    "Run Pinput as the same user as the runtime, or as an administrator.".to_owned()
 }
 
-/// On Linux (at least the amd64 version),
-/// the area of memory containing `pstate` is an anonymous mapping.
-#[cfg(target_os = "linux")]
-fn is_pico8_data_segment(map: &MapRange) -> Result<bool, Error> {
-    let map_permissions_rw_only = map.is_read() && map.is_write() && !map.is_exec();
-    Ok(map_permissions_rw_only && map.filename().is_none())
+/// Does the whole GPIO block starting at `address` fit inside a region?
+fn region_contains_gpio(start: usize, size: usize, address: usize) -> bool {
+    let end = start.saturating_add(size);
+    address >= start
+        && address
+            .checked_add(PINPUT_GPIO_SIZE)
+            .is_some_and(|gpio_end| gpio_end <= end)
 }
 
-fn is_wasm4_process(process: &Process) -> Result<bool, Error> {
-    is_wasm4_exe(process.exe())
+/// A running process that one of our runtime definitions matches.
+struct RuntimeProcess {
+    pid: Pid,
+    flavor: Arc<RuntimeDefinition>,
+    exe: PathBuf,
 }
 
-const WASM4_EXECUTABLE_NAMES: &[&str] = &[
-    "wasm4-mac",
-    "wasm4-linux",
-    "wasm4-windows.exe",
-    "wasm4",
-    "wasm4.exe",
-    "wasm4_glfw",
-    "wasm4_glfw.exe",
-];
-
-/// Detect WASM-4 native runtimes.
-/// Not the `w4` binary, but one of the platform-specific ones packed inside it:
-/// https://github.com/aduros/wasm4/blob/main/cli/lib/run-native.js
-fn is_wasm4_exe(path: &Path) -> Result<bool, Error> {
-    if let Some(file_name) = path.file_name() {
-        Ok(WASM4_EXECUTABLE_NAMES.contains(&file_name.to_string_lossy().as_ref()))
-    } else {
-        Ok(false)
-    }
+/// Every running process that matches one of `definitions`, with the first one that matches it.
+/// Never Pinput itself or its helper, which have copies of runtime RAM of their own.
+fn find_runtime_processes(
+    definitions: &[Arc<RuntimeDefinition>],
+    access: &MemoryAccess,
+) -> Vec<RuntimeProcess> {
+    let mut own_pids = access.helper_pids();
+    own_pids.push(std::process::id() as Pid);
+    let system =
+        System::new_with_specifics(RefreshKind::new().with_processes(ProcessRefreshKind::new()));
+    #[cfg(not(target_os = "macos"))]
+    exe_inspector::forget_exited_processes(
+        &system
+            .processes()
+            .values()
+            .map(|process| (process.pid().as_u32(), process.start_time()))
+            .collect(),
+    );
+    system
+        .processes()
+        .iter()
+        .filter(|(process_id, _)| !own_pids.contains(&(process_id.as_u32() as Pid)))
+        .filter_map(|(process_id, process)| {
+            let flavor = definitions
+                .iter()
+                .find(|definition| definition.matches_process(process).unwrap_or(false))?;
+            Some(RuntimeProcess {
+                pid: process_id.as_u32() as Pid,
+                flavor: flavor.clone(),
+                exe: program_path(process),
+            })
+        })
+        .collect()
+}
+
+/// Search the runtime's data segments for Pinput magic.
+/// With a bounded scanner, big runtimes may take several calls to search completely.
+fn find_gpio_address(
+    pid: Pid,
+    handle: &ProcessHandle,
+    flavor: &RuntimeDefinition,
+    exe: &Path,
+    scanner: &mut MagicScanner,
+) -> Result<usize, Error> {
+    let regions: Vec<Region> = proc_maps::get_process_maps(pid)
+        .map_err(|err| Error::from_access(pid, exe, None, err))?
+        .iter()
+        .filter(|map| flavor.is_data_segment(map, exe))
+        .map(Region::from)
+        .collect();
+    scanner
+        .find(
+            pid,
+            regions,
+            |address, len| process_memory::copy_address(address, len, handle),
+            |address, region| score_gpio_candidate(handle, flavor, address, region),
+            flavor.ram.map_or(0, |layout| layout.max_score()),
+        )
+        .map_err(|err| Error::from_access(pid, exe, Some(err.address), err.source))?
+        .ok_or_else(|| Error::PinputNotEnabled {
+            pid,
+            path: exe.to_path_buf(),
+            source: scanner.read_error(pid),
+        })
 }
 
-/// WASM-4 cartridge memory is in an anonymous mapping on macOS and Windows.
-#[cfg(not(target_os = "linux"))]
-fn is_wasm4_data_segment(map: &MapRange) -> Result<bool, Error> {
-    let map_permissions_rw_only = map.is_read() && map.is_write() && !map.is_exec();
-    Ok(map_permissions_rw_only && map.filename().is_none())
+/// How much the memory around magic at `address` looks like the runtime's RAM,
+/// or why the magic can't be in the runtime's GPIO block.
+fn score_gpio_candidate(
+    handle: &ProcessHandle,
+    flavor: &RuntimeDefinition,
+    address: usize,
+    region: &Region,
+) -> Result<u32, String> {
+    let Some(layout) = flavor.ram else {
+        return Ok(0);
+    };
+    let start = layout
+        .ram_start(address)
+        .filter(|&start| region.contains(start, layout.size))
+        .ok_or_else(|| {
+            format!(
+                "{flavor} RAM around it wouldn't fit in the mapping at {:#x}",
+                region.start
+            )
+        })?;
+    let ram = process_memory::copy_address(start, layout.size, handle)
+        .map_err(|err| format!("couldn't read {flavor} RAM around it: {err}"))?;
+    Ok(layout.score(&ram))
 }
 
-/// WASM-4 cartridge memory is in an anonymous mapping on Linux too,
-/// but it gets reported as being in the file "[heap]", which appears to be a procfs quirk:
-/// https://github.com/rbspy/proc-maps/blob/1acf96a/src/linux_maps.rs#L123
-#[cfg(target_os = "linux")]
-fn is_wasm4_data_segment(map: &MapRange) -> Result<bool, Error> {
-    let map_permissions_rw_only = map.is_read() && map.is_write() && !map.is_exec();
-    let map_is_anonymous_or_heap = match map.filename() {
-        None => true,
-        Some(x) if x.to_string_lossy() == "[heap]" => true,
-        _ => false,
+/// Look up one process, without the cost of listing all of them.
+fn refresh_process(system: &mut System, pid: Pid) -> Result<&Process, Error> {
+    let process_id = sysinfo::Pid::from_u32(pid as u32);
+    let not_found = || Error::ProcessNotFound {
+        pid,
+        path: None,
+        source: None,
     };
-    Ok(map_permissions_rw_only && map_is_anonymous_or_heap)
+    if !system.refresh_process_specifics(process_id, ProcessRefreshKind::new()) {
+        return Err(not_found());
+    }
+    system.process(process_id).ok_or_else(not_found)
+}
+
+/// When a process started, to tell it apart from a later process that reuses its PID.
+pub fn process_start_time(pid: Pid) -> Option<u64> {
+    let mut system = System::new();
+    let process = refresh_process(&mut system, pid).ok()?;
+    Some(process.start_time())
+}
+
+/// Which of `definitions` a process is, if any, and the executable it's running.
+fn identify(
+    definitions: &[Arc<RuntimeDefinition>],
+    pid: Pid,
+) -> Result<(Option<Arc<RuntimeDefinition>>, PathBuf), Error> {
+    let mut system = System::new();
+    let process = refresh_process(&mut system, pid)?;
+    let flavor = definitions
+        .iter()
+        .find(|definition| definition.matches_process(process).unwrap_or(false))
+        .cloned();
+    Ok((flavor, program_path(process)))
 }
 
-/// Return offset of Pinput magic from memory region's base.
-fn find_pinput_magic(handle: &ProcessHandle, map: &MapRange) -> Result<usize, Error> {
-    let data = process_memory::copy_address(
-        map.start(),
-        map.size(),
-        handle,
-    )?;
-    memmem::find(&data, PINPUT_MAGIC.as_bytes())
-        .ok_or(Error::PinputMagicNotFound)
+/// Which of `definitions` a process is, if any.
+pub fn identify_runtime(
+    definitions: &[Arc<RuntimeDefinition>],
+    pid: Pid,
+) -> Result<Option<Arc<RuntimeDefinition>>, Error> {
+    Ok(identify(definitions, pid)?.0)
 }
 
+/// Search a runtime for Pinput magic all over again, for when its GPIO block has moved.
+/// This reads a lot of memory, so it's best run off the main task.
+fn locate_gpio(pid: Pid, flavor: &RuntimeDefinition) -> Result<usize, Error> {
+    let mut system = System::new();
+    let process = refresh_process(&mut system, pid)?;
+    let exe = program_path(process);
+    let handle = open_process(pid, &exe)?;
+    find_gpio_address(pid, &handle, flavor, &exe, &mut MagicScanner::unbounded())
+}
+
+/// Searches for the GPIO block from scratch on another thread. See [`MemoryAccess::relocator`].
+pub type Relocator = Box<dyn FnOnce() -> Result<usize, Error> + Send>;
+
+/// How we get into runtimes' memory.
 #[derive(Debug)]
-pub enum RuntimeFlavor {
-    Pico8,
-    Wasm4,
+pub enum MemoryAccess {
+    /// Read and write it ourselves, keeping track of searches in progress.
+    Direct(MagicScanner),
+    /// Ask a helper process that has the privileges to.
+    #[cfg(target_os = "linux")]
+    Helper(Arc<HelperClient>),
 }
 
-impl Display for RuntimeFlavor {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let name = match &self {
-            RuntimeFlavor::Pico8 => "PICO-8",
-            RuntimeFlavor::Wasm4 => "WASM-4",
-        };
-        f.write_str(name)
+impl MemoryAccess {
+    /// Start a new scan pass over the runtimes in `live`. The helper keeps its own budget.
+    fn start_pass(&mut self, live: &[Pid]) {
+        if let MemoryAccess::Direct(scanner) = self {
+            scanner.start_pass(live);
+        }
+    }
+
+    /// PIDs of the helper processes, if we're using them.
+    fn helper_pids(&self) -> Vec<Pid> {
+        match self {
+            MemoryAccess::Direct(_) => vec![],
+            #[cfg(target_os = "linux")]
+            MemoryAccess::Helper(helper) => helper.pids(),
+        }
+    }
+
+    /// Something to find a connected runtime's GPIO block again after it's moved.
+    pub fn relocator(&self, connection: &RuntimeConnection) -> Relocator {
+        let pid = connection.pid;
+        match self {
+            MemoryAccess::Direct(_) => {
+                let flavor = connection.flavor.clone();
+                Box::new(move || locate_gpio(pid, &flavor))
+            }
+            #[cfg(target_os = "linux")]
+            MemoryAccess::Helper(helper) => {
+                let helper = helper.clone();
+                let exe = connection.exe.clone();
+                Box::new(move || helper.find(pid, None, &exe))
+            }
+        }
     }
 }
 
-/// Encapsulates a connection to a PICO-8 or WASM-4 process.
+/// Make sure a GPIO address we were given lies in a mapping we can read and write,
+/// and that the runtime has put Pinput magic there.
+fn check_gpio_address(
+    pid: Pid,
+    handle: &ProcessHandle,
+    exe: &Path,
+    address: usize,
+) -> Result<(), Error> {
+    let mapped = proc_maps::get_process_maps(pid)
+        .map_err(|err| Error::from_access(pid, exe, None, err))?
+        .iter()
+        .any(|map| {
+            map.is_read()
+                && map.is_write()
+                && region_contains_gpio(map.start(), map.size(), address)
+        });
+    if !mapped {
+        return Err(Error::GpioAddressNotMapped { pid, address });
+    }
+
+    let data = process_memory::copy_address(address, PINPUT_MAGIC.len(), handle)
+        .map_err(|err| Error::from_access(pid, exe, Some(address), err))?;
+    if data != PINPUT_MAGIC {
+        return Err(Error::PinputNotEnabledAt {
+            pid,
+            path: exe.to_path_buf(),
+            address,
+        });
+    }
+    Ok(())
+}
+
+/// Get a handle for reading and writing a process's memory.
+/// On Linux this can't fail, and access is only checked when we use it.
+fn open_process(pid: Pid, exe: &Path) -> Result<ProcessHandle, Error> {
+    pid.try_into_process_handle()
+        .map_err(|err| Error::from_access(pid, exe, None, err))
+}
+
+/// Encapsulates a connection to a PICO-8, WASM-4, or TIC-80 process.
 #[derive(Debug)]
 pub struct RuntimeConnection {
-    /// The PID is just for display right now.
+    /// Shown in messages, and how we find the runtime again to check or search its memory.
     pub pid: Pid,
-    /// Also just for display right now.
-    pub flavor: RuntimeFlavor,
-    /// First 16 bytes of GPIO mapped as a UUID.
-    pub gpio_as_uuid: DataMember<Uuid>,
-    /// All 128 bytes of GPIO mapped as an array of gamepads.
-    pub gpio_as_gamepads: DataMember<PinputGamepadArray>,
+    /// Which kind of runtime this is. Displays as its name.
+    pub flavor: Arc<RuntimeDefinition>,
+    /// The runtime's executable, for error messages.
+    pub exe: PathBuf,
+    /// The runtime's GPIO block.
+    pub gpio: Box<dyn GpioBackend>,
+    /// Where the GPIO block is in the runtime's memory, if it's in another process.
+    pub gpio_address: Option<usize>,
 }
 
 impl RuntimeConnection {
     pub fn new(
         pid: Pid,
-        flavor: RuntimeFlavor,
+        flavor: Arc<RuntimeDefinition>,
+        exe: PathBuf,
         handle: ProcessHandle,
         gpio_address: usize,
+    ) -> RuntimeConnection {
+        RuntimeConnection {
+            gpio_address: Some(gpio_address),
+            ..Self::with_backend(
+                pid,
+                flavor,
+                exe,
+                ProcessMemoryGpio::new(handle, gpio_address),
+            )
+        }
+    }
+
+    /// Connect through any GPIO backend, such as an in-memory buffer.
+    pub fn with_backend(
+        pid: Pid,
+        flavor: Arc<RuntimeDefinition>,
+        exe: PathBuf,
+        gpio: impl GpioBackend + 'static,
     ) -> RuntimeConnection {
         RuntimeConnection {
             pid,
             flavor,
-            gpio_as_uuid: DataMember::new_offset(handle, vec![gpio_address]),
-            gpio_as_gamepads: DataMember::new_offset(handle, vec![gpio_address]),
+            exe,
+            gpio: Box::new(gpio),
+            gpio_address: None,
         }
     }
 
-    pub fn try_new() -> Result<Self, Error> {
-        let system = System::new_with_specifics(
-            RefreshKind::new().with_processes(ProcessRefreshKind::new())
-        );
-
-        let (runtime_pid, runtime_flavor) = system.processes().iter()
-            .find_map(|(process_id, process)| {
-                let pid = process_id.as_u32() as Pid;
-                if is_pico8_process(process).unwrap_or(false) {
-                    Some((pid, RuntimeFlavor::Pico8))
-                } else if is_wasm4_process(process).unwrap_or(false) {
-                    Some((pid, RuntimeFlavor::Wasm4))
-                } else {
-                    None
-                }
-            })
-            .ok_or(Error::NoProcessesFound)?;
-        let runtime_handle = runtime_pid.try_into_process_handle()?;
-
-        let gpio_address = proc_maps::get_process_maps(runtime_pid)?.into_iter()
-            .filter(|map| {
-                match runtime_flavor {
-                    RuntimeFlavor::Pico8 => is_pico8_data_segment(map),
-                    RuntimeFlavor::Wasm4 => is_wasm4_data_segment(map),
-                }.unwrap_or(false)
-            })
-            .find_map(|map| {
-                // TODO: some permission errors should probably break out of this,
-                //  since that might mean we don't have the right entitlement or capability.
-                let offset = find_pinput_magic(&runtime_handle, &map).ok()?;
-                Some(map.start() + offset)
-            })
-            .ok_or(Error::PinputNotEnabled(runtime_pid))?;
+    /// Is the GPIO block still in a mapping with the permissions the runtime's RAM should have?
+    /// Always true for GPIO that isn't in another process.
+    pub fn is_mapped(&self) -> Result<bool, Error> {
+        let Some(address) = self.gpio_address else {
+            return Ok(true);
+        };
+        let permissions = self.flavor.mappings.permissions;
+        let maps = proc_maps::get_process_maps(self.pid)
+            .map_err(|err| Error::from_access(self.pid, &self.exe, None, err))?;
+        Ok(maps.iter().any(|map| {
+            permissions.matches(map.is_read(), map.is_write(), map.is_exec())
+                && region_contains_gpio(map.start(), map.size(), address)
+        }))
+    }
 
-        Ok(RuntimeConnection::new(
-            runtime_pid,
-            runtime_flavor,
-            runtime_handle,
+    /// Point the connection at the GPIO block's new address after the runtime moved it.
+    pub fn relocate(&mut self, gpio_address: usize) -> Result<(), Error> {
+        self.gpio.relocate(gpio_address)?;
+        self.gpio_address = Some(gpio_address);
+        Ok(())
+    }
+
+    /// Find the GPIO block of every runtime we can, except the ones in `skip`,
+    /// which are already connected or wouldn't let us in.
+    /// Returns one result for each of the other runtime processes, in no particular order.
+    /// Starts a new scan pass, so all of them share one scan budget.
+    /// This can read a lot of memory, so it's best run off the main task,
+    /// and the runtimes it finds connected to with [`RuntimeConnection::open`] afterwards.
+    pub fn find_all(
+        definitions: &[Arc<RuntimeDefinition>],
+        skip: &[Pid],
+        access: &mut MemoryAccess,
+    ) -> Vec<Result<FoundRuntime, Error>> {
+        let runtime_processes: Vec<RuntimeProcess> = find_runtime_processes(definitions, access)
+            .into_iter()
+            .filter(|runtime_process| !skip.contains(&runtime_process.pid))
+            .collect();
+        let live: Vec<Pid> = runtime_processes
+            .iter()
+            .map(|runtime_process| runtime_process.pid)
+            .collect();
+        access.start_pass(&live);
+        runtime_processes
+            .into_iter()
+            .map(|RuntimeProcess { pid, flavor, exe }| find(pid, flavor, exe, None, access))
+            .collect()
+    }
+
+    /// Find the GPIO block of a specific process instead of every runtime we can.
+    /// If we were given a GPIO address, just check it.
+    /// A process that none of `definitions` match gets searched as an unknown runtime.
+    /// Starts a new scan pass, as [`RuntimeConnection::find_all`] does.
+    pub fn find(
+        definitions: &[Arc<RuntimeDefinition>],
+        pid: Pid,
+        gpio_address: Option<usize>,
+        access: &mut MemoryAccess,
+    ) -> Result<FoundRuntime, Error> {
+        access.start_pass(&[pid]);
+        let (flavor, exe) = identify(definitions, pid)?;
+        let flavor = flavor.unwrap_or_else(|| Arc::new(RuntimeDefinition::unknown()));
+        find(pid, flavor, exe, gpio_address, access)
+    }
+
+    /// [`RuntimeConnection::find`] and [`RuntimeConnection::open`] in one go.
+    pub fn try_attach(
+        definitions: &[Arc<RuntimeDefinition>],
+        pid: Pid,
+        gpio_address: Option<usize>,
+        access: &mut MemoryAccess,
+    ) -> Result<Self, Error> {
+        let found = Self::find(definitions, pid, gpio_address, access)?;
+        Self::open(found, access)
+    }
+
+    /// Connect to a runtime whose GPIO block we've found, through the same `access` that found it.
+    pub fn open(found: FoundRuntime, access: &MemoryAccess) -> Result<Self, Error> {
+        let FoundRuntime {
+            pid,
+            flavor,
+            exe,
             gpio_address,
-        ))
+        } = found;
+        let connection = match access {
+            MemoryAccess::Direct(_) => {
+                #[cfg(target_os = "linux")]
+                {
+                    let gpio = ProcMemGpio::open(pid, gpio_address)
+                        .map_err(|err| Error::from_access(pid, &exe, None, err))?;
+                    Self::with_backend(pid, flavor, exe, gpio)
+                }
+                #[cfg(not(target_os = "linux"))]
+                {
+                    let handle = open_process(pid, &exe)?;
+                    Self::new(pid, flavor, exe, handle, gpio_address)
+                }
+            }
+            // The helper found the GPIO block, and reads and writes it from now on.
+            #[cfg(target_os = "linux")]
+            MemoryAccess::Helper(helper) => {
+                Self::with_backend(pid, flavor, exe, HelperGpio::new(helper.clone(), pid))
+            }
+        };
+        Ok(RuntimeConnection {
+            gpio_address: Some(gpio_address),
+            ..connection
+        })
     }
 }
 
+/// A runtime whose GPIO block we've found, but haven't connected to yet.
+#[derive(Debug)]
+pub struct FoundRuntime {
+    pub pid: Pid,
+    pub flavor: Arc<RuntimeDefinition>,
+    pub exe: PathBuf,
+    pub gpio_address: usize,
+}
+
+/// Find the GPIO block of a runtime process, or check the one we were told about.
+fn find(
+    pid: Pid,
+    flavor: Arc<RuntimeDefinition>,
+    exe: PathBuf,
+    gpio_address: Option<usize>,
+    access: &mut MemoryAccess,
+) -> Result<FoundRuntime, Error> {
+    let gpio_address = match access {
+        MemoryAccess::Direct(scanner) => {
+            let handle = open_process(pid, &exe)?;
+            match gpio_address {
+                Some(address) => {
+                    check_gpio_address(pid, &handle, &exe, address)?;
+                    address
+                }
+                None => find_gpio_address(pid, &handle, &flavor, &exe, scanner)?,
+            }
+        }
+        #[cfg(target_os = "linux")]
+        MemoryAccess::Helper(helper) => helper.find(pid, gpio_address, &exe)?,
+    };
+    Ok(FoundRuntime {
+        pid,
+        flavor,
+        exe,
+        gpio_address,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gpio_must_fit_in_region() {
+        assert!(region_contains_gpio(0x1000, 0x1000, 0x1000));
+        assert!(region_contains_gpio(
+            0x1000,
+            0x1000,
+            0x2000 - PINPUT_GPIO_SIZE
+        ));
+        assert!(!region_contains_gpio(
+            0x1000,
+            0x1000,
+            0x2000 - PINPUT_GPIO_SIZE + 1
+        ));
+        assert!(!region_contains_gpio(0x1000, 0x1000, 0xfff));
+        assert!(!region_contains_gpio(0x1000, 0x1000, usize::MAX));
+    }
+
+    #[test]
+    fn access_errors_are_classified() {
+        let exe = Path::new("/usr/bin/pico8");
+        let classify = |address, err| Error::from_access(42, exe, address, err);
+
+        let denied = classify(Some(0x1000), io::ErrorKind::PermissionDenied.into());
+        assert!(matches!(denied, Error::AccessDenied { pid: 42, ref path, .. } if path == exe));
+        assert!(denied.to_string().contains(&access_remedy()));
+
+        let gone = classify(None, io::ErrorKind::NotFound.into());
+        assert!(matches!(
+            gone,
+            Error::ProcessNotFound { pid: 42, path: Some(ref path), source: Some(_) } if path == exe
+        ));
+        assert!(gone.to_string().contains("/usr/bin/pico8"));
+        #[cfg(unix)]
+        assert!(matches!(
+            classify(Some(0x1000), io::Error::from_raw_os_error(3)),
+            Error::ProcessNotFound { pid: 42, .. }
+        ));
+        assert!(matches!(
+            classify(Some(0x1000), io::Error::other("bad address")),
+            Error::RegionVanished {
+                address: 0x1000,
+                ..
+            }
+        ));
+        assert!(matches!(
+            classify(None, io::Error::other("bad address")),
+            Error::IOError(_)
+        ));
+    }
+}
@@ -0,0 +1,764 @@
+//! Which processes are runtimes, and where in their memory to look for Pinput magic.
+//!
+//! The built-in runtimes are described with the same [`RuntimeDefinition`]s that can be loaded
+//! from a config file, so forks, custom builds, and renamed binaries work without recompiling.
+
+use glob::Pattern;
+use proc_maps::MapRange;
+use regex::Regex;
+use serde::Deserialize;
+#[cfg(target_os = "macos")]
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use sysinfo::{PidExt, Process, ProcessExt};
+
+#[cfg(not(target_os = "macos"))]
+use crate::exe_inspector;
+use crate::exe_inspector::ProcessKey;
+use crate::runtime_connection::Error;
+#[cfg(target_os = "linux")]
+use crate::wine;
+
+/// Matches a whole name: either a glob like `"wasm4*"`, or a regex like `{"regex": "tic80(-.+)?"}`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "NamePatternSource")]
+pub enum NamePattern {
+    Glob(Pattern),
+    Regex(Regex),
+}
+
+/// How a [`NamePattern`] is written in a config file.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NamePatternSource {
+    Glob(String),
+    Regex { regex: String },
+}
+
+impl TryFrom<NamePatternSource> for NamePattern {
+    type Error = String;
+
+    fn try_from(source: NamePatternSource) -> Result<Self, Self::Error> {
+        match source {
+            NamePatternSource::Glob(glob) => Pattern::new(&glob)
+                .map(NamePattern::Glob)
+                .map_err(|err| format!("bad glob `{glob}`: {err}")),
+            NamePatternSource::Regex { regex } => Regex::new(&format!("^(?:{regex})$"))
+                .map(NamePattern::Regex)
+                .map_err(|err| format!("bad regex `{regex}`: {err}")),
+        }
+    }
+}
+
+impl NamePattern {
+    /// For the built-in definitions, which don't have typos.
+    fn glob(glob: &str) -> Self {
+        NamePattern::Glob(Pattern::new(glob).expect("built-in glob should be valid"))
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            NamePattern::Glob(pattern) => pattern.matches(name),
+            NamePattern::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// Permissions a memory mapping must have, written like in `/proc/<pid>/maps`:
+/// `"rw-"` is readable, writable, and not executable. A `?` allows either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Permissions {
+    pub read: Option<bool>,
+    pub write: Option<bool>,
+    pub exec: Option<bool>,
+}
+
+impl Permissions {
+    /// Where runtimes keep their RAM.
+    pub const READ_WRITE: Permissions = Permissions {
+        read: Some(true),
+        write: Some(true),
+        exec: Some(false),
+    };
+
+    pub fn matches(&self, read: bool, write: bool, exec: bool) -> bool {
+        self.read.is_none_or(|read_| read_ == read)
+            && self.write.is_none_or(|write_| write_ == write)
+            && self.exec.is_none_or(|exec_| exec_ == exec)
+    }
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Permissions::READ_WRITE
+    }
+}
+
+impl TryFrom<String> for Permissions {
+    type Error = String;
+
+    fn try_from(permissions: String) -> Result<Self, Self::Error> {
+        let flag = |c: char, set: char| match c {
+            _ if c == set => Ok(Some(true)),
+            '-' => Ok(Some(false)),
+            '?' => Ok(None),
+            _ => Err(format!(
+                "bad permissions `{permissions}`: expected `{set}`, `-`, or `?`"
+            )),
+        };
+        match permissions.chars().collect::<Vec<_>>()[..] {
+            [read, write, exec] => Ok(Permissions {
+                read: flag(read, 'r')?,
+                write: flag(write, 'w')?,
+                exec: flag(exec, 'x')?,
+            }),
+            _ => Err(format!(
+                "bad permissions `{permissions}`: expected three characters like `rw-`"
+            )),
+        }
+    }
+}
+
+/// What's behind a memory mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MappingKind {
+    /// Not backed by a file.
+    Anonymous,
+    /// The process heap. Linux reports this as the pseudo-file `[heap]`;
+    /// elsewhere, it's anonymous.
+    Heap,
+    /// Backed by the runtime's own executable, like its data segment.
+    Executable,
+    /// Backed by any real file.
+    File,
+}
+
+/// Which of a runtime's memory mappings to search for Pinput magic.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MappingFilter {
+    #[serde(default)]
+    pub permissions: Permissions,
+    pub kinds: Vec<MappingKind>,
+}
+
+impl MappingFilter {
+    pub fn matches(&self, map: &MapRange, exe: &Path) -> bool {
+        self.permissions
+            .matches(map.is_read(), map.is_write(), map.is_exec())
+            && self.matches_file(map.filename(), exe)
+    }
+
+    /// Check only what's behind a mapping.
+    fn matches_file(&self, filename: Option<&Path>, exe: &Path) -> bool {
+        self.kinds.iter().any(|kind| match (kind, filename) {
+            (MappingKind::Anonymous, None) => true,
+            (MappingKind::Heap, Some(path)) => path == Path::new("[heap]"),
+            (MappingKind::Executable, Some(path)) => path == exe,
+            // Linux pseudo-files like `[stack]` aren't files.
+            (MappingKind::File, Some(path)) => !path.to_string_lossy().starts_with('['),
+            _ => false,
+        })
+    }
+}
+
+/// Where a runtime's GPIO block sits in its RAM, to tell the real one
+/// from stray copies of Pinput magic elsewhere in memory, like old Lua strings or freed buffers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RamLayout {
+    /// Size of the runtime's RAM, all of which has to be in the same mapping as the magic.
+    pub size: usize,
+    /// Offset of the GPIO block from the start of RAM.
+    pub gpio_offset: usize,
+    /// What else to expect in RAM.
+    #[serde(default)]
+    pub contents: RamContents,
+}
+
+/// Runtime-specific checks of what's in RAM around the GPIO block.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RamContents {
+    /// Nothing to check besides the layout.
+    #[default]
+    Any,
+    /// PICO-8 draw state: https://pico-8.fandom.com/wiki/Memory#Draw_state
+    Pico8,
+    /// WASM-4 system registers: https://wasm4.org/docs/reference/memory
+    Wasm4,
+}
+
+impl RamLayout {
+    /// Where RAM would start if the GPIO block were at `gpio_address`.
+    pub fn ram_start(&self, gpio_address: usize) -> Option<usize> {
+        gpio_address.checked_sub(self.gpio_offset)
+    }
+
+    /// How much `ram` looks like this runtime's RAM: one point for each check that passes.
+    /// Cartridges can put nearly anything in RAM, so failing a check doesn't rule it out.
+    pub fn score(&self, ram: &[u8]) -> u32 {
+        self.checks(ram)
+            .into_iter()
+            .filter(|&check| check == Some(true))
+            .count() as u32
+    }
+
+    /// The score of RAM that passes every check.
+    pub fn max_score(&self) -> u32 {
+        self.checks(&[]).len() as u32
+    }
+
+    /// Results of the checks, or `None` for checks that `ram` is too short for.
+    fn checks(&self, ram: &[u8]) -> Vec<Option<bool>> {
+        match self.contents {
+            RamContents::Any => vec![],
+            RamContents::Pico8 => vec![
+                // Screen palette entries are colors 0-15, or 128-143 from the secret palette.
+                ram.get(0x5f10..0x5f20)
+                    .map(|palette| palette.iter().all(|&color| color & 0x70 == 0)),
+                // The clipping rectangle is on the 128x128 screen and not inside out.
+                ram.get(0x5f20..0x5f24).map(|clip| {
+                    clip[0] <= clip[2] && clip[1] <= clip[3] && clip[2] <= 128 && clip[3] <= 128
+                }),
+            ],
+            RamContents::Wasm4 => vec![
+                // Palette entries are 0xRRGGBB, stored little-endian, so their top bytes are 0.
+                ram.get(0x04..0x14)
+                    .map(|palette| palette.chunks_exact(4).all(|color| color[3] == 0)),
+                // Only three mouse buttons.
+                ram.get(0x1e).map(|&buttons| buttons < 0x08),
+            ],
+        }
+    }
+}
+
+/// Describes one kind of runtime: how to recognize its processes,
+/// and where in its memory a cartridge's Pinput magic might be.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeDefinition {
+    /// Shown in messages, like `connected: PICO-8, PID 18649`.
+    pub name: String,
+    /// Executable file names.
+    #[serde(default)]
+    pub executables: Vec<NamePattern>,
+    /// Bundle IDs of macOS apps whose main executable is the runtime.
+    #[serde(default)]
+    pub bundle_ids: Vec<NamePattern>,
+    /// Also recognize executables that look like PICO-8 inside, such as exported standalone
+    /// cartridges, which can have any name. Not needed on macOS, where those have bundle IDs.
+    #[serde(default)]
+    pub pico8_contents: bool,
+    pub mappings: MappingFilter,
+    /// How to check that Pinput magic is in the runtime's RAM.
+    /// Without this, the first magic we find is assumed to be in the right place.
+    #[serde(default)]
+    pub ram: Option<RamLayout>,
+}
+
+impl Display for RuntimeDefinition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl RuntimeDefinition {
+    /// Used for processes we were told to attach to but don't recognize.
+    /// Searches every read-write mapping that isn't some other file.
+    pub fn unknown() -> Self {
+        RuntimeDefinition {
+            name: "unknown runtime".to_owned(),
+            executables: Vec::new(),
+            bundle_ids: Vec::new(),
+            pico8_contents: false,
+            mappings: MappingFilter {
+                permissions: Permissions::READ_WRITE,
+                kinds: vec![
+                    MappingKind::Anonymous,
+                    MappingKind::Heap,
+                    MappingKind::Executable,
+                ],
+            },
+            ram: None,
+        }
+    }
+
+    pub fn matches_process(&self, process: &Process) -> Result<bool, Error> {
+        let key = (process.pid().as_u32(), process.start_time());
+        self.matches_program(&program_path(process), Some(key))
+    }
+
+    pub fn matches_exe(&self, path: &Path) -> Result<bool, Error> {
+        self.matches_program(path, None)
+    }
+
+    /// `process` is the one running `path`, if we know.
+    fn matches_program(
+        &self,
+        path: &Path,
+        #[cfg_attr(target_os = "macos", allow(unused_variables))] process: Option<ProcessKey>,
+    ) -> Result<bool, Error> {
+        let file_name = path
+            .file_name()
+            .map(|file_name| file_name.to_string_lossy())
+            .unwrap_or_default();
+        if self
+            .executables
+            .iter()
+            .any(|pattern| pattern.matches(&file_name))
+        {
+            return Ok(true);
+        }
+
+        #[cfg(target_os = "macos")]
+        if !self.bundle_ids.is_empty() {
+            if let Some(bundle_id) = main_executable_bundle_id(path)? {
+                if self
+                    .bundle_ids
+                    .iter()
+                    .any(|pattern| pattern.matches(&bundle_id))
+                {
+                    return Ok(true);
+                }
+            }
+        }
+
+        #[cfg(not(target_os = "macos"))]
+        if self.pico8_contents && exe_inspector::is_pico8_exe(path, process)? {
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Should we search this mapping of a process running `exe` for Pinput magic?
+    pub fn is_data_segment(&self, map: &MapRange, exe: &Path) -> bool {
+        self.mappings.matches(map, exe)
+    }
+}
+
+/// The executable a process is running. For Windows runtimes under Wine or Proton,
+/// that's the Windows program, not the Wine loader, which isn't interesting.
+pub fn program_path(process: &Process) -> PathBuf {
+    #[cfg(target_os = "linux")]
+    if let Some(program) = wine::windows_program_path(
+        process.exe(),
+        process.cmd(),
+        process.environ(),
+        process.cwd(),
+    ) {
+        return program;
+    }
+
+    process.exe().to_path_buf()
+}
+
+/// Subset of `Info.plist` for a macOS app.
+/// See <https://developer.apple.com/library/archive/documentation/CoreFoundation/Conceptual/CFBundles/BundleTypes/BundleTypes.html#//apple_ref/doc/uid/10000123i-CH101-SW19>.
+#[cfg(target_os = "macos")]
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InfoPlist {
+    /// Name of the app's main executable file.
+    c_f_bundle_executable: String,
+    /// Bundle ID, used for identifying a specific app to various system APIs, and to Pinput.
+    c_f_bundle_identifier: String,
+}
+
+/// If this file is inside a macOS app bundle, return the path to that bundle.
+#[cfg(target_os = "macos")]
+fn find_app_bundle_path(path: &Path) -> Result<&Path, Error> {
+    let app_extension = Some(OsStr::new("app"));
+    path.ancestors()
+        .find(|path| path.extension() == app_extension)
+        .ok_or_else(|| Error::AppBundle {
+            path: PathBuf::from(path),
+        })
+}
+
+/// Assume the path is for a macOS app bundle.
+/// Load the `Info.plist` for that bundle.
+#[cfg(target_os = "macos")]
+fn load_info_plist(app_bundle_path: &Path) -> Result<InfoPlist, plist::Error> {
+    let info_plist_path = app_bundle_path.join("Contents/Info.plist");
+    plist::from_file(info_plist_path)
+}
+
+/// If this file is the main executable of a macOS app bundle, return the bundle ID.
+#[cfg(target_os = "macos")]
+fn main_executable_bundle_id(path: &Path) -> Result<Option<String>, Error> {
+    let app_bundle_path = find_app_bundle_path(path)?;
+    let info_plist = load_info_plist(app_bundle_path)?;
+
+    let bundle_main_executable_path = app_bundle_path
+        .join("Contents/MacOS")
+        .join(info_plist.c_f_bundle_executable);
+    if path == bundle_main_executable_path {
+        Ok(Some(info_plist.c_f_bundle_identifier))
+    } else {
+        Ok(None)
+    }
+}
+
+fn globs(globs: &[&str]) -> Vec<NamePattern> {
+    globs.iter().map(|glob| NamePattern::glob(glob)).collect()
+}
+
+/// PICO-8 RAM is in the executable's data segment on macOS and Windows,
+/// but on Linux (at least the amd64 version), the area of memory containing `pstate`
+/// is an anonymous mapping. Windows PICO-8 under Wine still keeps it in the data segment
+/// of `pico8.exe`, which Wine maps from the file like any other.
+fn pico8_mappings() -> Vec<MappingKind> {
+    if cfg!(target_os = "linux") {
+        vec![MappingKind::Anonymous, MappingKind::Executable]
+    } else {
+        vec![MappingKind::Executable]
+    }
+}
+
+/// Bundle IDs only matter on macOS. Elsewhere, leaving them out keeps Pinput's own executable
+/// from containing the strings that [`exe_inspector`] looks for.
+#[cfg(target_os = "macos")]
+fn pico8_bundle_ids() -> Vec<NamePattern> {
+    globs(&["com.lexaloffle.pico8", "com.pico8_author.*"])
+}
+
+#[cfg(not(target_os = "macos"))]
+fn pico8_bundle_ids() -> Vec<NamePattern> {
+    Vec::new()
+}
+
+/// WASM-4 cartridge memory and TIC-80 RAM are in anonymous mappings or the heap.
+/// Linux reports the heap as being in the file "[heap]", which appears to be a procfs quirk:
+/// https://github.com/rbspy/proc-maps/blob/1acf96a/src/linux_maps.rs#L123
+fn anonymous_or_heap_mappings() -> Vec<MappingKind> {
+    vec![MappingKind::Anonymous, MappingKind::Heap]
+}
+
+/// The runtimes Pinput supports out of the box, in the order they're tried.
+pub fn builtin_runtime_definitions() -> Vec<RuntimeDefinition> {
+    vec![
+        RuntimeDefinition {
+            name: "PICO-8".to_owned(),
+            executables: globs(&["pico8", "pico8.exe"]),
+            bundle_ids: pico8_bundle_ids(),
+            pico8_contents: true,
+            mappings: MappingFilter {
+                permissions: Permissions::READ_WRITE,
+                kinds: pico8_mappings(),
+            },
+            ram: Some(RamLayout {
+                size: 0x10000,
+                gpio_offset: 0x5f80,
+                contents: RamContents::Pico8,
+            }),
+        },
+        // Not the `w4` binary, but one of the platform-specific ones packed inside it:
+        // https://github.com/aduros/wasm4/blob/main/cli/lib/run-native.js
+        RuntimeDefinition {
+            name: "WASM-4".to_owned(),
+            executables: globs(&[
+                "wasm4-mac",
+                "wasm4-linux",
+                "wasm4-windows.exe",
+                "wasm4",
+                "wasm4.exe",
+                "wasm4_glfw",
+                "wasm4_glfw.exe",
+            ]),
+            bundle_ids: Vec::new(),
+            pico8_contents: false,
+            mappings: MappingFilter {
+                permissions: Permissions::READ_WRITE,
+                kinds: anonymous_or_heap_mappings(),
+            },
+            // Cartridges use the reserved bytes after the system registers for GPIO.
+            ram: Some(RamLayout {
+                size: 0x10000,
+                gpio_offset: 0x20,
+                contents: RamContents::Wasm4,
+            }),
+        },
+        // The same name on every platform. On macOS, this is the main executable of `tic80.app`.
+        RuntimeDefinition {
+            name: "TIC-80".to_owned(),
+            executables: globs(&["tic80", "tic80.exe"]),
+            bundle_ids: Vec::new(),
+            pico8_contents: false,
+            mappings: MappingFilter {
+                permissions: Permissions::READ_WRITE,
+                kinds: anonymous_or_heap_mappings(),
+            },
+            // 96 KiB of RAM, with GPIO in the unused bytes after the system font.
+            ram: Some(RamLayout {
+                size: 0x18000,
+                gpio_offset: 0x14e04,
+                contents: RamContents::Any,
+            }),
+        },
+    ]
+}
+
+/// On-disk format of the runtime config file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuntimeConfigFile {
+    runtimes: Vec<RuntimeDefinition>,
+}
+
+/// The runtimes described in `path`, if there is one, followed by the built-in ones.
+/// Runtimes from the file come first so they can claim processes a built-in one would also match.
+pub fn load_runtime_definitions(path: Option<&Path>) -> Vec<Arc<RuntimeDefinition>> {
+    let configured = match path {
+        Some(path) => match fs::read(path) {
+            Ok(contents) => match serde_json::from_slice::<RuntimeConfigFile>(&contents) {
+                Ok(file) => file.runtimes,
+                Err(err) => {
+                    println!("Ignoring unreadable runtime file {}: {err}", path.display());
+                    Vec::new()
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                println!("Failed to read runtime file {}: {err}", path.display());
+                Vec::new()
+            }
+        },
+        None => Vec::new(),
+    };
+    configured
+        .into_iter()
+        .chain(builtin_runtime_definitions())
+        .map(Arc::new)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> RuntimeDefinition {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn parses_config_entries() {
+        let definition = parse(
+            r#"{
+                "name": "WASM-4 (custom)",
+                "executables": ["wasm4_glfw*", {"regex": "w4-.+"}],
+                "mappings": {"permissions": "rw?", "kinds": ["anonymous", "heap"]}
+            }"#,
+        );
+        assert_eq!(definition.to_string(), "WASM-4 (custom)");
+        assert!(definition
+            .matches_exe(Path::new("/opt/wasm4_glfw-debug"))
+            .unwrap());
+        assert!(definition.matches_exe(Path::new("/opt/w4-fork")).unwrap());
+        // Regexes have to match the whole name.
+        assert!(!definition
+            .matches_exe(Path::new("/opt/not-w4-fork"))
+            .unwrap());
+        assert_eq!(
+            definition.mappings.permissions,
+            Permissions {
+                read: Some(true),
+                write: Some(true),
+                exec: None,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_config_entries() {
+        let bad = [
+            r#"{"name": "x", "executables": [{"regex": "("}], "mappings": {"kinds": []}}"#,
+            r#"{"name": "x", "mappings": {"permissions": "rwz", "kinds": []}}"#,
+            r#"{"name": "x", "mappings": {"kinds": ["stack"]}}"#,
+            r#"{"name": "x", "executable": ["typo"], "mappings": {"kinds": []}}"#,
+        ];
+        for json in bad {
+            assert!(
+                serde_json::from_str::<RuntimeDefinition>(json).is_err(),
+                "{json}"
+            );
+        }
+    }
+
+    #[test]
+    fn mapping_kinds() {
+        let exe = Path::new("/opt/pico-8/pico8");
+        let filter = |kinds| MappingFilter {
+            permissions: Permissions::READ_WRITE,
+            kinds,
+        };
+        let anonymous_or_heap = filter(vec![MappingKind::Anonymous, MappingKind::Heap]);
+        assert!(anonymous_or_heap.matches_file(None, exe));
+        assert!(anonymous_or_heap.matches_file(Some(Path::new("[heap]")), exe));
+        assert!(!anonymous_or_heap.matches_file(Some(exe), exe));
+
+        let executable = filter(vec![MappingKind::Executable]);
+        assert!(executable.matches_file(Some(exe), exe));
+        assert!(!executable.matches_file(Some(Path::new("/usr/lib/libSDL2.so")), exe));
+
+        let file = filter(vec![MappingKind::File]);
+        assert!(file.matches_file(Some(Path::new("/usr/lib/libSDL2.so")), exe));
+        assert!(!file.matches_file(Some(Path::new("[stack]")), exe));
+        assert!(!file.matches_file(None, exe));
+    }
+
+    /// Which lines of a `/proc/<pid>/maps` listing `definition` would search, for a process running `exe`.
+    #[cfg(target_os = "linux")]
+    fn searched_maps<'a>(
+        definition: &RuntimeDefinition,
+        maps: &'a str,
+        exe: &Path,
+    ) -> Vec<&'a str> {
+        maps.lines()
+            .filter(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let perms = fields[1].as_bytes();
+                let filename = fields.get(5).map(Path::new);
+                definition.mappings.permissions.matches(
+                    perms[0] == b'r',
+                    perms[1] == b'w',
+                    perms[2] == b'x',
+                ) && definition.mappings.matches_file(filename, exe)
+            })
+            .collect()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn wine_pico8_data_segment_is_searched() {
+        // A Wine prefix whose C: drive is the usual symlink to `drive_c`.
+        let prefix = std::env::temp_dir().join(format!("pinput-wine-test-{}", std::process::id()));
+        let program_dir = prefix.join("drive_c/pico-8");
+        fs::create_dir_all(&program_dir).unwrap();
+        fs::create_dir_all(prefix.join("dosdevices")).unwrap();
+        std::os::unix::fs::symlink("../drive_c", prefix.join("dosdevices/c:")).unwrap();
+        fs::write(program_dir.join("pico8.exe"), b"MZ").unwrap();
+
+        let exe = wine::windows_program_path(
+            Path::new("/usr/bin/wine64-preloader"),
+            &["C:\\pico-8\\pico8.exe".to_owned()],
+            &[format!("WINEPREFIX={}", prefix.display())],
+            Path::new("/"),
+        )
+        .unwrap();
+        let pe = program_dir.canonicalize().unwrap().join("pico8.exe");
+        let maps = format!(
+            "\
+7bd000000000-7bd000001000 r-xp 00000000 00:1f 1001 /usr/bin/wine64-preloader
+7bd000001000-7bd000002000 rw-p 00001000 00:1f 1001 /usr/bin/wine64-preloader
+140000000-140001000 r--p 00000000 00:1f 2002 {pe}
+140001000-1400c0000 r-xp 00001000 00:1f 2002 {pe}
+1400c0000-140200000 rw-p 000c0000 00:1f 2002 {pe}
+170000000-170100000 rw-p 00000000 00:00 0
+6ffffc000000-6ffffc100000 rw-p 00010000 00:1f 3003 {prefix}/drive_c/windows/system32/ntdll.dll
+55d000000000-55d000100000 rw-p 00000000 00:00 0 [heap]
+7ffd00000000-7ffd00021000 rw-p 00000000 00:00 0 [stack]",
+            pe = pe.display(),
+            prefix = prefix.display(),
+        );
+
+        let pico8 = &builtin_runtime_definitions()[0];
+        let searched = searched_maps(pico8, &maps, &exe);
+        fs::remove_dir_all(&prefix).unwrap();
+
+        assert_eq!(exe, pe);
+        assert_eq!(
+            searched,
+            [
+                format!(
+                    "1400c0000-140200000 rw-p 000c0000 00:1f 2002 {}",
+                    pe.display()
+                ),
+                "170000000-170100000 rw-p 00000000 00:00 0".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn builtin_runtimes_recognize_their_executables() {
+        let builtins = builtin_runtime_definitions();
+        let name_of = |path: &str| {
+            builtins
+                .iter()
+                .find(|definition| definition.matches_exe(Path::new(path)).unwrap_or(false))
+                .map(|definition| definition.name.as_str())
+        };
+        assert_eq!(name_of("/usr/bin/pico8"), Some("PICO-8"));
+        assert_eq!(name_of("/tmp/wasm4_glfw"), Some("WASM-4"));
+        assert_eq!(name_of("/usr/bin/tic80"), Some("TIC-80"));
+        assert_eq!(name_of("/usr/bin/tic80-launcher"), None);
+    }
+
+    #[test]
+    fn ram_layouts_are_scored() {
+        let builtins = builtin_runtime_definitions();
+        let pico8 = builtins[0].ram.unwrap();
+        assert_eq!(pico8.ram_start(0x5f80), Some(0));
+        assert_eq!(pico8.ram_start(0x5f7f), None);
+
+        let mut ram = vec![0u8; pico8.size];
+        ram[0x5f10..0x5f20]
+            .copy_from_slice(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 0x80]);
+        ram[0x5f20..0x5f24].copy_from_slice(&[0, 0, 128, 128]);
+        assert_eq!(pico8.score(&ram), 2);
+        assert_eq!(pico8.max_score(), 2);
+        ram[0x5f22] = 200;
+        assert_eq!(pico8.score(&ram), 1);
+        // Too short to check anything.
+        assert_eq!(pico8.score(&ram[..0x5f00]), 0);
+
+        let wasm4 = builtins[1].ram.unwrap();
+        assert_eq!(wasm4.max_score(), 2);
+        assert_eq!(builtins[2].ram.unwrap().max_score(), 0);
+        let mut ram = vec![0u8; wasm4.size];
+        ram[0x04..0x08].copy_from_slice(&[0xcf, 0xf8, 0xe0, 0x00]);
+        // The mouse is on the right of the screen.
+        ram[0x1a..0x1c].copy_from_slice(&150i16.to_le_bytes());
+        ram[0x1e] = 0x01;
+        assert_eq!(wasm4.score(&ram), 2);
+        ram[0x1e] = 0xff;
+        assert_eq!(wasm4.score(&ram), 1);
+        ram[0x13] = 0xff;
+        assert_eq!(wasm4.score(&ram), 0);
+
+        let layout: RamLayout =
+            serde_json::from_str(r#"{"size": 65536, "gpio_offset": 24448, "contents": "pico8"}"#)
+                .unwrap();
+        assert_eq!(layout, pico8);
+    }
+
+    #[test]
+    fn configured_runtimes_come_first() {
+        let path =
+            std::env::temp_dir().join(format!("pinput-runtimes-test-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{"runtimes": [{"name": "PICO-8 fork", "executables": ["pico8"], "mappings": {"kinds": ["anonymous"]}}]}"#,
+        )
+        .unwrap();
+        let definitions = load_runtime_definitions(Some(&path));
+        let _ = fs::remove_file(&path);
+
+        let names: Vec<&str> = definitions
+            .iter()
+            .map(|definition| definition.name.as_str())
+            .collect();
+        assert_eq!(names, ["PICO-8 fork", "PICO-8", "WASM-4", "TIC-80"]);
+
+        let missing = load_runtime_definitions(Some(Path::new("/nonexistent/runtimes.json")));
+        assert_eq!(missing.len(), builtin_runtime_definitions().len());
+    }
+}
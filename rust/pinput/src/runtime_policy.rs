@@ -0,0 +1,58 @@
+//! Deciding which controllers each connected runtime gets.
+
+/// How a runtime's gamepad slots are filled when several runtimes are connected.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimePolicy {
+    /// The runtime sees every controller, in the same slot it has in Pinput.
+    /// Runtimes that share a controller all get to rumble it, and each motor runs as hard
+    /// as the strongest of them asks for.
+    #[default]
+    MirrorAll,
+    /// Controllers are dealt out between all runtimes with this policy, in the order they connected:
+    /// with two runtimes, slots 0, 2, 4... go to the first as its slots 0, 1, 2...,
+    /// and slots 1, 3, 5... go to the second.
+    SplitBySlot,
+}
+
+impl RuntimePolicy {
+    /// Pick the sources for one runtime out of the sources for every slot.
+    /// `share` is this runtime's position among the `shares` runtimes splitting slots,
+    /// and is ignored for runtimes that mirror everything.
+    pub fn select<T>(
+        self,
+        sources: impl IntoIterator<Item = T>,
+        share: usize,
+        shares: usize,
+    ) -> impl Iterator<Item = T> {
+        sources
+            .into_iter()
+            .enumerate()
+            .filter(move |(slot, _)| match self {
+                RuntimePolicy::MirrorAll => true,
+                RuntimePolicy::SplitBySlot => slot % shares.max(1) == share,
+            })
+            .map(|(_, source)| source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mirror_all_keeps_every_slot() {
+        let selected: Vec<_> = RuntimePolicy::MirrorAll.select(0..5, 1, 2).collect();
+        assert_eq!(selected, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn split_by_slot_deals_slots_out_in_turn() {
+        let first: Vec<_> = RuntimePolicy::SplitBySlot.select(0..5, 0, 2).collect();
+        let second: Vec<_> = RuntimePolicy::SplitBySlot.select(0..5, 1, 2).collect();
+        assert_eq!(first, [0, 2, 4]);
+        assert_eq!(second, [1, 3]);
+
+        let alone: Vec<_> = RuntimePolicy::SplitBySlot.select(0..3, 0, 1).collect();
+        assert_eq!(alone, [0, 1, 2]);
+    }
+}
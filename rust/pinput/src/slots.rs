@@ -0,0 +1,336 @@
+//! The fixed set of gamepad slots that the cartridge sees, and the devices occupying them.
+
+use pinput_protocol::PINPUT_MAX_GAMEPADS;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Devices keep their slot until they're removed, and freed slots are reused lowest first,
+/// so unplugging one gamepad doesn't move any of the others.
+#[derive(Debug)]
+pub struct Slots<T> {
+    slots: [Option<T>; PINPUT_MAX_GAMEPADS],
+}
+
+impl<T> Default for Slots<T> {
+    fn default() -> Self {
+        Self {
+            slots: Default::default(),
+        }
+    }
+}
+
+impl<T> Slots<T> {
+    /// Put a device in the lowest free slot and return that slot's index.
+    /// If all slots are taken, hand the device back.
+    pub fn insert(&mut self, device: T) -> Result<usize, T> {
+        self.insert_preferring(device, None)
+    }
+
+    /// Like [`Slots::insert`], but use the `preferred` slot if it's free.
+    pub fn insert_preferring(&mut self, device: T, preferred: Option<usize>) -> Result<usize, T> {
+        let free_slot = preferred
+            .filter(|&index| self.is_free(index))
+            .or_else(|| self.slots.iter().position(Option::is_none));
+        match free_slot {
+            Some(index) => {
+                self.slots[index] = Some(device);
+                Ok(index)
+            }
+            None => Err(device),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.slots.get(index)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        self.slots.get_mut(index)?.as_mut()
+    }
+
+    /// Exchange the contents of two slots, either of which may be free.
+    pub fn swap(&mut self, a: usize, b: usize) {
+        self.slots.swap(a, b);
+    }
+
+    pub fn is_free(&self, index: usize) -> bool {
+        matches!(self.slots.get(index), Some(None))
+    }
+
+    /// Index of the first slot whose device matches `predicate`.
+    pub fn position(&self, mut predicate: impl FnMut(&T) -> bool) -> Option<usize> {
+        self.slots
+            .iter()
+            .position(|slot| slot.as_ref().is_some_and(&mut predicate))
+    }
+
+    /// Free the first slot whose device matches `predicate`,
+    /// returning that slot's index and the device that was in it.
+    pub fn remove_by(&mut self, predicate: impl FnMut(&T) -> bool) -> Option<(usize, T)> {
+        let index = self.position(predicate)?;
+        self.slots[index].take().map(|device| (index, device))
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Option<T>> {
+        self.slots.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Option<T>> {
+        self.slots.iter_mut()
+    }
+}
+
+/// Identifies a physical controller across reconnections and runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ControllerId {
+    /// SDL joystick GUID, which identifies the model and how it's connected.
+    pub guid: String,
+    /// Tells identical controllers apart, if the driver reports one.
+    pub serial: Option<String>,
+}
+
+/// On-disk format of [`SlotMemory`].
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SlotMemoryFile {
+    slots: [Option<ControllerId>; PINPUT_MAX_GAMEPADS],
+}
+
+/// Remembers which controller last sat in each slot, so that it gets the same slot back
+/// when it reconnects. Saved to a file, if we have one, every time an assignment changes.
+#[derive(Debug, Default)]
+pub struct SlotMemory {
+    path: Option<PathBuf>,
+    slots: [Option<ControllerId>; PINPUT_MAX_GAMEPADS],
+}
+
+impl SlotMemory {
+    /// Start from the assignments saved in `path`, if there are any.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let slots = match &path {
+            Some(path) => match fs::read(path) {
+                Ok(contents) => match serde_json::from_slice::<SlotMemoryFile>(&contents) {
+                    Ok(file) => file.slots,
+                    Err(err) => {
+                        println!("Ignoring unreadable slot file {}: {err}", path.display());
+                        Default::default()
+                    }
+                },
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Default::default(),
+                Err(err) => {
+                    println!("Failed to read slot file {}: {err}", path.display());
+                    Default::default()
+                }
+            },
+            None => Default::default(),
+        };
+        Self { path, slots }
+    }
+
+    /// The slot this controller last sat in, if that slot is free now.
+    /// Identical controllers without serials may have several; we take the lowest free one.
+    pub fn preferred_slot<T>(&self, id: &ControllerId, slots: &Slots<T>) -> Option<usize> {
+        self.slots
+            .iter()
+            .enumerate()
+            .position(|(index, remembered)| remembered.as_ref() == Some(id) && slots.is_free(index))
+    }
+
+    /// Record that this controller now sits in `slot`, having moved from `previous` if given,
+    /// and forget where it sat before. A controller with a serial can only be in one place,
+    /// so all of its other entries go; without one, only `previous` does,
+    /// since the rest may belong to identical controllers.
+    pub fn remember(&mut self, id: ControllerId, slot: usize, previous: Option<usize>) {
+        let mut changed = false;
+        for (index, remembered) in self.slots.iter_mut().enumerate() {
+            let stale = index != slot
+                && remembered.as_ref() == Some(&id)
+                && (id.serial.is_some() || Some(index) == previous);
+            if stale {
+                *remembered = None;
+                changed = true;
+            }
+        }
+        if self.slots[slot].as_ref() != Some(&id) {
+            self.slots[slot] = Some(id);
+            changed = true;
+        }
+        if changed {
+            self.save_or_log();
+        }
+    }
+
+    /// Record that nobody sits in `slot` anymore, because its controller moved elsewhere.
+    pub fn forget(&mut self, slot: usize) {
+        if self.slots[slot].take().is_some() {
+            self.save_or_log();
+        }
+    }
+
+    fn save_or_log(&self) {
+        if let Err(err) = self.save() {
+            println!("Failed to save controller slots: {err}");
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = SlotMemoryFile {
+            slots: self.slots.clone(),
+        };
+        fs::write(path, serde_json::to_vec_pretty(&file)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_slots_are_reused_lowest_first() {
+        let mut slots = Slots::default();
+        for device in 0..3 {
+            assert_eq!(slots.insert(device), Ok(device));
+        }
+
+        assert_eq!(slots.remove_by(|&device| device == 1), Some((1, 1)));
+        assert_eq!(slots.remove_by(|&device| device == 1), None);
+        // The device after the removed one stays put.
+        assert_eq!(slots.position(|&device| device == 2), Some(2));
+
+        assert_eq!(slots.insert(10), Ok(1));
+        assert_eq!(slots.insert(11), Ok(3));
+    }
+
+    #[test]
+    fn full_slots_give_the_device_back() {
+        let mut slots = Slots::default();
+        for device in 0..PINPUT_MAX_GAMEPADS {
+            slots.insert(device).unwrap();
+        }
+        assert_eq!(slots.insert(99), Err(99));
+    }
+
+    fn id(guid: &str, serial: Option<&str>) -> ControllerId {
+        ControllerId {
+            guid: guid.to_owned(),
+            serial: serial.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn reconnected_controller_gets_its_slot_back() {
+        let mut memory = SlotMemory::default();
+        let mut slots = Slots::default();
+        let pad = id("pad", Some("1"));
+
+        slots.insert_preferring('a', None).unwrap();
+        slots.insert_preferring('b', None).unwrap();
+        let slot = slots.insert_preferring('c', None).unwrap();
+        memory.remember(pad.clone(), slot, None);
+        slots.remove_by(|&device| device == 'c');
+        // Someone else unplugs, leaving a lower slot free.
+        slots.remove_by(|&device| device == 'a');
+
+        let preferred = memory.preferred_slot(&pad, &slots);
+        assert_eq!(preferred, Some(2));
+        assert_eq!(slots.insert_preferring('c', preferred), Ok(2));
+        // An unknown controller takes the lowest free slot.
+        let unknown = memory.preferred_slot(&id("other", None), &slots);
+        assert_eq!(slots.insert_preferring('d', unknown), Ok(0));
+    }
+
+    #[test]
+    fn taken_preferred_slot_falls_back_to_lowest_free() {
+        let mut memory = SlotMemory::default();
+        let mut slots = Slots::default();
+        memory.remember(id("pad", None), 0, None);
+        slots.insert('x').unwrap();
+
+        assert_eq!(memory.preferred_slot(&id("pad", None), &slots), None);
+        assert_eq!(slots.insert_preferring('y', Some(0)), Ok(1));
+    }
+
+    #[test]
+    fn identical_controllers_share_remembered_slots() {
+        let mut memory = SlotMemory::default();
+        let mut slots = Slots::default();
+        memory.remember(id("pad", None), 1, None);
+        memory.remember(id("pad", None), 3, None);
+
+        let first = memory.preferred_slot(&id("pad", None), &slots);
+        assert_eq!(slots.insert_preferring('a', first), Ok(1));
+        let second = memory.preferred_slot(&id("pad", None), &slots);
+        assert_eq!(slots.insert_preferring('b', second), Ok(3));
+    }
+
+    #[test]
+    fn moved_controller_is_remembered_in_its_new_slot() {
+        let mut memory = SlotMemory::default();
+        let mut slots = Slots::default();
+        let pad = id("pad", None);
+        slots.insert('a').unwrap();
+        memory.remember(pad.clone(), 0, None);
+
+        slots.swap(0, 2);
+        memory.remember(pad.clone(), 2, Some(0));
+
+        assert_eq!(slots.get(2), Some(&'a'));
+        assert!(slots.is_free(0));
+        assert_eq!(
+            memory.preferred_slot(&pad, &Slots::<()>::default()),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn old_slots_are_forgotten() {
+        let mut memory = SlotMemory::default();
+        let pad = id("pad", Some("1"));
+        let twin = id("twin", None);
+        memory.remember(pad.clone(), 1, None);
+        memory.remember(twin.clone(), 4, None);
+        memory.remember(twin.clone(), 5, None);
+
+        // A controller with a serial can only have one slot, even without knowing where it was.
+        memory.remember(pad.clone(), 2, None);
+        // Slot 1 is free, but it isn't the pad's anymore.
+        assert_eq!(
+            memory.preferred_slot(&pad, &Slots::<()>::default()),
+            Some(2)
+        );
+        assert_eq!(memory.slots[1], None);
+
+        // A controller without one only gives up the slot it moved from.
+        memory.remember(twin.clone(), 6, Some(5));
+        let twins: Vec<usize> = (0..PINPUT_MAX_GAMEPADS)
+            .filter(|&slot| memory.slots[slot].as_ref() == Some(&twin))
+            .collect();
+        assert_eq!(twins, [4, 6]);
+    }
+
+    #[test]
+    fn assignments_persist_across_runs() {
+        let path = std::env::temp_dir()
+            .join(format!("pinput-slots-test-{}", std::process::id()))
+            .join("slots.json");
+        let pad = id("pad", Some("serial"));
+
+        let mut memory = SlotMemory::load(Some(path.clone()));
+        memory.remember(pad.clone(), 5, None);
+
+        let memory = SlotMemory::load(Some(path.clone()));
+        assert_eq!(
+            memory.preferred_slot(&pad, &Slots::<()>::default()),
+            Some(5)
+        );
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+//! Finding the Windows program inside a Wine or Proton process.
+//!
+//! The executable of a Wine process is the Wine loader, which tells us nothing.
+//! Wine replaces the process's command line with the Windows path of the program it's running,
+//! so we map that back to a path we can open.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Executable names of the Wine loader, including the copies that Proton ships.
+const WINE_LOADER_NAMES: &[&str] = &["wine", "wine64", "wine-preloader", "wine64-preloader"];
+
+/// If `exe` is the Wine loader, return the path of the Windows program it's running.
+/// `cmd`, `environ` and `cwd` are the process's command line, environment and working directory.
+pub fn windows_program_path(
+    exe: &Path,
+    cmd: &[String],
+    environ: &[String],
+    cwd: &Path,
+) -> Option<PathBuf> {
+    let loader = exe.file_name()?.to_str()?;
+    if !WINE_LOADER_NAMES.contains(&loader) {
+        return None;
+    }
+    let program = cmd
+        .iter()
+        .find(|arg| arg.to_ascii_lowercase().ends_with(".exe"))?;
+    let path = unix_path(program, environ, cwd)?;
+    // Memory maps name the file Wine mapped the program from with symlinks resolved,
+    // so resolve the prefix's drive symlinks too.
+    Some(fs::canonicalize(&path).unwrap_or(path))
+}
+
+/// Map a Windows path like `C:\pico-8\pico8.exe` to a Unix path through the Wine prefix's
+/// drive symlinks. Paths without a drive letter are from before Wine rewrote the command line.
+fn unix_path(path: &str, environ: &[String], cwd: &Path) -> Option<PathBuf> {
+    let bytes = path.as_bytes();
+    let has_drive =
+        bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && bytes[2] == b'\\';
+    if !has_drive {
+        return Some(cwd.join(path));
+    }
+
+    let drive = bytes[0].to_ascii_lowercase() as char;
+    let rest = path[3..].replace('\\', "/");
+    let root = match wine_prefix(environ) {
+        Some(prefix) => prefix.join("dosdevices").join(format!("{drive}:")),
+        // Wine always maps Z: to the filesystem root.
+        None if drive == 'z' => PathBuf::from("/"),
+        None => return None,
+    };
+    Some(root.join(rest))
+}
+
+/// Proton and most launchers set `WINEPREFIX`. Plain Wine defaults to `~/.wine`.
+fn wine_prefix(environ: &[String]) -> Option<PathBuf> {
+    let var = |name: &str| {
+        environ
+            .iter()
+            .find_map(|entry| entry.strip_prefix(name)?.strip_prefix('='))
+    };
+    var("WINEPREFIX")
+        .map(PathBuf::from)
+        .or_else(|| var("HOME").map(|home| Path::new(home).join(".wine")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(strings: &[&str]) -> Vec<String> {
+        strings.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn maps_drive_through_prefix() {
+        let path = windows_program_path(
+            Path::new("/usr/bin/wine64-preloader"),
+            &strings(&["C:\\Games\\pico-8\\pico8.exe", "-run", "cart.p8"]),
+            &strings(&["HOME=/home/p8", "WINEPREFIX=/home/p8/prefix"]),
+            Path::new("/"),
+        );
+        assert_eq!(
+            path,
+            Some(PathBuf::from(
+                "/home/p8/prefix/dosdevices/c:/Games/pico-8/pico8.exe"
+            ))
+        );
+    }
+
+    #[test]
+    fn defaults_to_home_prefix() {
+        let path = windows_program_path(
+            Path::new("/opt/proton/files/bin/wine64"),
+            &strings(&["D:\\mygame.EXE"]),
+            &strings(&["HOME=/home/p8"]),
+            Path::new("/"),
+        );
+        assert_eq!(
+            path,
+            Some(PathBuf::from("/home/p8/.wine/dosdevices/d:/mygame.EXE"))
+        );
+    }
+
+    #[test]
+    fn maps_z_drive_to_root_without_prefix() {
+        let path = windows_program_path(
+            Path::new("/usr/bin/wine"),
+            &strings(&["Z:\\home\\p8\\mygame.exe"]),
+            &[],
+            Path::new("/"),
+        );
+        assert_eq!(path, Some(PathBuf::from("/home/p8/mygame.exe")));
+    }
+
+    #[test]
+    fn relative_paths_use_working_directory() {
+        let path = windows_program_path(
+            Path::new("/usr/bin/wine"),
+            &strings(&["wine", "mygame.exe"]),
+            &[],
+            Path::new("/home/p8/games"),
+        );
+        assert_eq!(path, Some(PathBuf::from("/home/p8/games/mygame.exe")));
+    }
+
+    #[test]
+    fn ignores_native_processes() {
+        let path = windows_program_path(
+            Path::new("/usr/bin/pico8"),
+            &strings(&["pico8", "launcher.exe"]),
+            &[],
+            Path::new("/"),
+        );
+        assert_eq!(path, None);
+    }
+}